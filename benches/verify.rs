@@ -0,0 +1,46 @@
+//! Benchmarks of signing-related operations on a representative chat payload.
+use blah::types::{ChatItem, ChatPayload};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use ed25519_dalek::SigningKey;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use uuid::Uuid;
+
+const BATCH_LEN: usize = 64;
+
+fn fixture(rng: &mut StdRng, i: u8) -> ChatItem {
+    let key = SigningKey::from_bytes(&[i; 32]);
+    let payload = ChatPayload {
+        room: Uuid::from_bytes([42; 16]),
+        sequence: None,
+        text: "The quick brown fox jumps over the lazy dog.".repeat(4),
+    };
+    ChatItem::sign(&key, rng, payload).unwrap()
+}
+
+fn bench(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(42);
+    let item = fixture(&mut rng, 0);
+    let batch = (0..BATCH_LEN as u8)
+        .map(|i| fixture(&mut rng, i))
+        .collect::<Vec<_>>();
+
+    c.bench_function("canonical_bytes", |b| {
+        b.iter(|| item.signee.canonical_bytes().unwrap())
+    });
+    c.bench_function("sign", |b| {
+        let key = SigningKey::from_bytes(&[0; 32]);
+        b.iter_batched(
+            || item.signee.payload.clone(),
+            |payload| ChatItem::sign(&key, &mut rng, payload).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    c.bench_function("verify", |b| b.iter(|| item.verify().unwrap()));
+    c.bench_function("verify_batch_64", |b| {
+        b.iter(|| batch.iter().try_for_each(|item| item.verify()).unwrap())
+    });
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
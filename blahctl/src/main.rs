@@ -4,6 +4,7 @@ use std::{fs, io};
 
 use anyhow::{Context, Result};
 use bitflags::Flags;
+use blah::db;
 use blah::types::{
     ChatPayload, CreateRoomPayload, MemberPermission, RoomAttrs, RoomMember, RoomMemberList,
     ServerPermission, UserKey, WithSig,
@@ -142,8 +143,6 @@ impl User {
     }
 }
 
-static INIT_SQL: &str = include_str!("../../init.sql");
-
 fn main() -> Result<()> {
     let cli = <Cli as clap::Parser>::parse();
 
@@ -156,8 +155,7 @@ fn main() -> Result<()> {
         }
         Command::Database { database, command } => {
             let conn = Connection::open(database).context("failed to open database")?;
-            conn.execute_batch(INIT_SQL)
-                .context("failed to initialize database")?;
+            db::init(&conn)?;
             main_db(conn, command)?;
         }
         Command::Api { url, command } => build_rt()?.block_on(main_api(url, command))?,
@@ -216,8 +214,8 @@ async fn main_api(api_url: Url, command: ApiCommand) -> Result<()> {
                 // The CLI does not support passing multiple members because `User` itself is a
                 // disjoint arg-group.
                 members: RoomMemberList(vec![RoomMember {
-                    permission: MemberPermission::ALL,
-                    user: UserKey(key.verifying_key().to_bytes()),
+                    permission: MemberPermission::OWNER,
+                    user: UserKey::from_signing_key(&key),
                 }]),
             };
             let payload = WithSig::sign(&key, &mut OsRng, payload)?;
@@ -238,7 +236,11 @@ async fn main_api(api_url: Url, command: ApiCommand) -> Result<()> {
             text,
         } => {
             let key = load_signing_key(&private_key_file)?;
-            let payload = ChatPayload { room, text };
+            let payload = ChatPayload {
+                room,
+                sequence: None,
+                text,
+            };
             let payload = WithSig::sign(&key, &mut OsRng, payload)?;
 
             let ret = client
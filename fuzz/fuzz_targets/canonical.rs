@@ -0,0 +1,14 @@
+//! Canonicalization must never panic, and must be idempotent on any parsable input.
+#![no_main]
+
+use blah::types::WithSig;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(item) = serde_json::from_slice::<WithSig<serde_json::Value>>(data) else {
+        return;
+    };
+    let canonical = serde_json::to_vec(&item).unwrap();
+    let item = serde_json::from_slice::<WithSig<serde_json::Value>>(&canonical).unwrap();
+    assert_eq!(serde_json::to_vec(&item).unwrap(), canonical);
+});
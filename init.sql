@@ -1,3 +1,4 @@
+-- When changing the schema, also add a migration in `src/db.rs`.
 PRAGMA journal_mode=WAL;
 PRAGMA foreign_keys=TRUE;
 
@@ -27,6 +28,21 @@ CREATE TABLE IF NOT EXISTS `room_item` (
     `uid`       INTEGER NOT NULL REFERENCES `user` ON DELETE RESTRICT,
     `timestamp` INTEGER NOT NULL,
     `nonce`     INTEGER NOT NULL,
+    `version`   INTEGER NOT NULL,
     `sig`       BLOB NOT NULL,
-    `message`   TEXT NOT NULL
+    `sequence`  INTEGER,
+    `message`   TEXT NOT NULL,
+    `received_at` INTEGER NOT NULL
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS `user_block` (
+    `uid`       INTEGER NOT NULL REFERENCES `user` ON DELETE CASCADE,
+    `target`    BLOB NOT NULL,
+    PRIMARY KEY (`uid`, `target`)
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS `room_pin` (
+    `rid`       INTEGER NOT NULL REFERENCES `room` ON DELETE CASCADE,
+    `target`    BLOB NOT NULL,
+    PRIMARY KEY (`rid`, `target`)
 ) STRICT;
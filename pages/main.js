@@ -9,6 +9,9 @@ let roomUuid = null;
 let feed = null;
 let keypair = null;
 
+// NB. Sync with `PROTOCOL_VERSION` in `src/types.rs`.
+const PROTOCOL_VERSION = 1;
+
 function bufToHex(buf) {
     return [...new Uint8Array(buf)]
         .map(x => x.toString(16).padStart(2, '0'))
@@ -185,6 +188,7 @@ async function signData(payload) {
         payload,
         timestamp,
         user: userKey,
+        version: PROTOCOL_VERSION,
     };
 
     const signeeBytes = (new TextEncoder()).encode(JSON.stringify(signee));
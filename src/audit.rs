@@ -0,0 +1,92 @@
+//! Tamper-evident append-only log of signed items.
+//!
+//! Each line is an NDJSON object carrying the item and the hash of the previous line, so
+//! altering or removing any line breaks the chain of every line after it.
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::types::WithSig;
+
+/// The `prev_hash` of the first line of a log.
+pub const GENESIS_HASH: [u8; 32] = [0; 32];
+
+#[derive(Serialize)]
+struct LogLine<'a, T> {
+    item: &'a WithSig<T>,
+    #[serde(with = "hex::serde")]
+    prev: [u8; 32],
+}
+
+#[derive(Deserialize)]
+struct RawLogLine {
+    #[serde(with = "hex::serde")]
+    prev: [u8; 32],
+}
+
+/// Serialize `item` into a log line following the line of hash `prev_hash`, and return the line
+/// including the trailing newline together with its hash for the next line.
+pub fn append_log_line<T: Serialize>(prev_hash: [u8; 32], item: &WithSig<T>) -> (String, [u8; 32]) {
+    let mut line = serde_json::to_string(&LogLine {
+        item,
+        prev: prev_hash,
+    })
+    .expect("serialization cannot fail");
+    line.push('\n');
+    let hash = Sha256::digest(&line).into();
+    (line, hash)
+}
+
+/// Check the hash chain of log `lines` in order, starting from `prev_hash`, and return the hash
+/// of the last line. Lines must include their trailing newlines.
+///
+/// Signatures of items are not verified here.
+pub fn verify_log_chain<'a>(
+    mut prev_hash: [u8; 32],
+    lines: impl IntoIterator<Item = &'a str>,
+) -> Result<[u8; 32]> {
+    for (i, line) in lines.into_iter().enumerate() {
+        let raw = serde_json::from_str::<RawLogLine>(line)
+            .with_context(|| format!("invalid log line #{i}"))?;
+        ensure!(raw.prev == prev_hash, "broken hash chain at line #{i}");
+        prev_hash = Sha256::digest(line).into();
+    }
+    Ok(prev_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::types::ChatPayload;
+
+    #[test]
+    fn hash_chain() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let mut hash = GENESIS_HASH;
+        let mut lines = Vec::new();
+        for text in ["a", "b", "c"] {
+            let chat = ChatPayload {
+                room: Uuid::nil(),
+                sequence: None,
+                text: text.into(),
+            };
+            let item = WithSig::sign(&key, &mut OsRng, chat).unwrap();
+            let (line, next) = append_log_line(hash, &item);
+            assert!(line.ends_with('\n') && !line[..line.len() - 1].contains('\n'));
+            lines.push(line);
+            hash = next;
+        }
+        let last = verify_log_chain(GENESIS_HASH, lines.iter().map(|s| &**s)).unwrap();
+        assert_eq!(last, hash);
+
+        lines[1] = lines[1].replace(r#""text":"b""#, r#""text":"x""#);
+        // The altered line itself still links to its predecessor, but the next one does not.
+        verify_log_chain(GENESIS_HASH, lines[..2].iter().map(|s| &**s)).unwrap();
+        let err = verify_log_chain(GENESIS_HASH, lines.iter().map(|s| &**s)).unwrap_err();
+        assert_eq!(err.to_string(), "broken hash chain at line #2");
+    }
+}
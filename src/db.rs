@@ -0,0 +1,177 @@
+//! Initialization and schema migration of the server database.
+use anyhow::{ensure, Context, Result};
+use rusqlite::{Connection, Transaction};
+
+static INIT_SQL: &str = include_str!("../init.sql");
+
+type Migration = fn(&Transaction<'_>) -> rusqlite::Result<()>;
+
+/// Migrations of the schema, where the `i`-th one upgrades a database from `PRAGMA user_version`
+/// `i` to `i + 1`. Databases from before versioning have version 0.
+///
+/// Fresh databases are created by `init.sql` in the latest schema and skip all migrations.
+static MIGRATIONS: &[Migration] = &[migrate_room_item_columns];
+
+/// Add columns of chats added since version 0. Unversioned databases may already have some of
+/// them, which are kept as-is.
+///
+/// Existing chats are signed in `LEGACY_VERSION` and have no sequence, and we use their
+/// timestamps as the best guess of the receive time.
+fn migrate_room_item_columns(txn: &Transaction<'_>) -> rusqlite::Result<()> {
+    let mut existing = Vec::new();
+    txn.pragma(None, "table_info", "room_item", |row| {
+        existing.push(row.get::<_, String>("name")?);
+        Ok(())
+    })?;
+    let has = |col: &str| existing.iter().any(|c| c == col);
+    if !has("version") {
+        txn.execute_batch(
+            "ALTER TABLE `room_item` ADD COLUMN `version` INTEGER NOT NULL DEFAULT 0",
+        )?;
+    }
+    if !has("sequence") {
+        txn.execute_batch("ALTER TABLE `room_item` ADD COLUMN `sequence` INTEGER")?;
+    }
+    if !has("received_at") {
+        txn.execute_batch(
+            r"
+            ALTER TABLE `room_item` ADD COLUMN `received_at` INTEGER NOT NULL DEFAULT 0;
+            UPDATE `room_item` SET `received_at` = `timestamp`;
+            ",
+        )?;
+    }
+    Ok(())
+}
+
+/// The schema version of `init.sql`.
+pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+/// Initialize the database, or bring an existing one to [`SCHEMA_VERSION`].
+pub fn init(conn: &Connection) -> Result<()> {
+    let version = conn
+        .pragma_query_value(None, "user_version", |row| row.get::<_, usize>(0))
+        .context("failed to get schema version")?;
+    ensure!(
+        version <= SCHEMA_VERSION,
+        "database schema version {version} is newer than the supported {SCHEMA_VERSION}",
+    );
+
+    let txn = conn.unchecked_transaction()?;
+    let existing = txn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM `sqlite_master` WHERE `type` = 'table' AND `name` = 'room_item')",
+        [],
+        |row| row.get::<_, bool>(0),
+    )?;
+    if existing {
+        for (from, migrate) in MIGRATIONS.iter().enumerate().skip(version) {
+            migrate(&txn)
+                .with_context(|| format!("failed to migrate schema from version {from}"))?;
+        }
+    }
+    txn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    txn.commit()?;
+
+    conn.execute_batch(INIT_SQL)
+        .context("failed to initialize database")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrate_unversioned() {
+        let conn = Connection::open_in_memory().unwrap();
+        // The `room_item` schema of version 0.
+        conn.execute_batch(
+            r"
+            CREATE TABLE `room_item` (
+                `cid`       INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+                `rid`       INTEGER NOT NULL,
+                `uid`       INTEGER NOT NULL,
+                `timestamp` INTEGER NOT NULL,
+                `nonce`     INTEGER NOT NULL,
+                `sig`       BLOB NOT NULL,
+                `message`   TEXT NOT NULL
+            ) STRICT;
+            INSERT INTO `room_item` (`rid`, `uid`, `timestamp`, `nonce`, `sig`, `message`)
+            VALUES (1, 1, 42, 0, x'00', 'hello');
+            ",
+        )
+        .unwrap();
+
+        init(&conn).unwrap();
+        let row = conn
+            .query_row(
+                "SELECT `version`, `sequence`, `received_at` FROM `room_item`",
+                [],
+                |row| {
+                    Ok((
+                        row.get::<_, u32>(0)?,
+                        row.get::<_, Option<u64>>(1)?,
+                        row.get::<_, u64>(2)?,
+                    ))
+                },
+            )
+            .unwrap();
+        assert_eq!(row, (crate::types::LEGACY_VERSION, None, 42));
+
+        // Idempotent once migrated.
+        init(&conn).unwrap();
+        let version = conn
+            .pragma_query_value(None, "user_version", |row| row.get::<_, usize>(0))
+            .unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn migrate_unversioned_partial() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            r"
+            CREATE TABLE `room_item` (
+                `cid`       INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+                `rid`       INTEGER NOT NULL,
+                `uid`       INTEGER NOT NULL,
+                `timestamp` INTEGER NOT NULL,
+                `nonce`     INTEGER NOT NULL,
+                `version`   INTEGER NOT NULL,
+                `sig`       BLOB NOT NULL,
+                `sequence`  INTEGER,
+                `message`   TEXT NOT NULL
+            ) STRICT;
+            INSERT INTO `room_item` (`rid`, `uid`, `timestamp`, `nonce`, `version`, `sig`, `message`)
+            VALUES (1, 1, 42, 0, 1, x'00', 'hello');
+            ",
+        )
+        .unwrap();
+
+        init(&conn).unwrap();
+        let row = conn
+            .query_row(
+                "SELECT `version`, `received_at` FROM `room_item`",
+                [],
+                |row| Ok((row.get::<_, u32>(0)?, row.get::<_, u64>(1)?)),
+            )
+            .unwrap();
+        assert_eq!(row, (1, 42));
+    }
+
+    #[test]
+    fn init_fresh() {
+        let conn = Connection::open_in_memory().unwrap();
+        init(&conn).unwrap();
+        init(&conn).unwrap();
+        conn.execute_batch(
+            r"
+            INSERT INTO `user` (`userkey`) VALUES (x'00');
+            INSERT INTO `room` (`ruuid`, `title`, `attrs`) VALUES (x'00', 'test', 0);
+            INSERT INTO `room_item`
+                (`rid`, `uid`, `timestamp`, `nonce`, `version`, `sig`, `message`, `received_at`)
+            VALUES (1, 1, 42, 0, 1, x'00', 'hello', 42);
+            ",
+        )
+        .unwrap();
+    }
+}
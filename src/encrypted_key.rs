@@ -0,0 +1,94 @@
+//! Passphrase-encrypted storage of [`SigningKey`]s.
+//!
+//! The encryption key is derived from the passphrase via Argon2id with its default parameters,
+//! and the secret key is sealed with XChaCha20-Poly1305. The derived key and the plaintext secret
+//! key are wiped from memory after use.
+use anyhow::{anyhow, Context, Result};
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use ed25519_dalek::{SigningKey, SECRET_KEY_LENGTH};
+use rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptedKey {
+    #[serde(with = "hex::serde")]
+    pub ciphertext: Vec<u8>,
+    #[serde(with = "hex::serde")]
+    pub nonce: [u8; NONCE_LEN],
+    #[serde(with = "hex::serde")]
+    pub salt: [u8; SALT_LEN],
+}
+
+fn derive_cipher(passphrase: &str, salt: &[u8; SALT_LEN]) -> Result<XChaCha20Poly1305> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key[..])
+        .map_err(|err| anyhow!("failed to derive key: {err}"))?;
+    Ok(XChaCha20Poly1305::new((&*key).into()))
+}
+
+impl EncryptedKey {
+    pub fn encrypt(key: &SigningKey, passphrase: &str, rng: &mut impl RngCore) -> Self {
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        rng.fill_bytes(&mut salt);
+        rng.fill_bytes(&mut nonce);
+        let secret = Zeroizing::new(key.to_bytes());
+        let ciphertext = derive_cipher(passphrase, &salt)
+            .expect("default parameters are valid")
+            .encrypt(XNonce::from_slice(&nonce), &secret[..])
+            .expect("encryption cannot fail");
+        Self {
+            ciphertext,
+            nonce,
+            salt,
+        }
+    }
+
+    pub fn decrypt(&self, passphrase: &str) -> Result<SigningKey> {
+        let plaintext = Zeroizing::new(
+            derive_cipher(passphrase, &self.salt)?
+                .decrypt(XNonce::from_slice(&self.nonce), &self.ciphertext[..])
+                .map_err(|_| anyhow!("wrong passphrase or corrupted key"))?,
+        );
+        let bytes = Zeroizing::new(
+            <[u8; SECRET_KEY_LENGTH]>::try_from(&plaintext[..])
+                .ok()
+                .context("invalid secret key length")?,
+        );
+        Ok(SigningKey::from_bytes(&bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let key = SigningKey::from_bytes(&[42; SECRET_KEY_LENGTH]);
+        let enc = EncryptedKey::encrypt(&key, "hunter2", &mut rand::rngs::OsRng);
+        let enc =
+            serde_json::from_str::<EncryptedKey>(&serde_json::to_string(&enc).unwrap()).unwrap();
+        assert_eq!(enc.decrypt("hunter2").unwrap().to_bytes(), key.to_bytes());
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let key = SigningKey::from_bytes(&[42; SECRET_KEY_LENGTH]);
+        let enc = EncryptedKey::encrypt(&key, "hunter2", &mut rand::rngs::OsRng);
+        let err = enc.decrypt("hunter3").unwrap_err();
+        assert_eq!(err.to_string(), "wrong passphrase or corrupted key");
+
+        let mut corrupted = enc.clone();
+        corrupted.ciphertext[0] ^= 1;
+        corrupted.decrypt("hunter2").unwrap_err();
+    }
+}
@@ -0,0 +1,81 @@
+//! Deduplicated storage of user keys for servers holding many room members.
+use std::collections::HashMap;
+
+use crate::types::UserKey;
+
+/// A compact handle to a key in a [`KeyInterner`].
+///
+/// Handles are only meaningful for the interner that created them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KeyHandle(u32);
+
+/// A pool of [`UserKey`]s, where each distinct key is stored once and referred by a 4-byte
+/// [`KeyHandle`] instead of the 32-byte key itself.
+///
+/// Keys are never removed, so handles stay valid for the lifetime of the interner.
+#[derive(Debug, Default)]
+pub struct KeyInterner {
+    handles: HashMap<UserKey, KeyHandle>,
+    keys: Vec<UserKey>,
+}
+
+impl KeyInterner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Get the handle of `key`, adding it to the pool if it is not interned yet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are already `u32::MAX` keys.
+    pub fn intern(&mut self, key: &UserKey) -> KeyHandle {
+        if let Some(&handle) = self.handles.get(key) {
+            return handle;
+        }
+        let handle = KeyHandle(u32::try_from(self.keys.len()).expect("too many keys"));
+        self.keys.push(key.clone());
+        self.handles.insert(key.clone(), handle);
+        handle
+    }
+
+    /// Get the handle of `key` if it is interned, without adding it.
+    pub fn lookup(&self, key: &UserKey) -> Option<KeyHandle> {
+        self.handles.get(key).copied()
+    }
+
+    /// Recover the key of `handle`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `handle` is not from this interner.
+    pub fn resolve(&self, handle: KeyHandle) -> &UserKey {
+        &self.keys[handle.0 as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intern_dedup() {
+        let mut pool = KeyInterner::new();
+        let a = pool.intern(&UserKey([1; 32]));
+        let b = pool.intern(&UserKey([2; 32]));
+        assert_ne!(a, b);
+        assert_eq!(pool.intern(&UserKey([1; 32])), a);
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.lookup(&UserKey([2; 32])), Some(b));
+        assert_eq!(pool.lookup(&UserKey([3; 32])), None);
+        assert_eq!(pool.resolve(a), &UserKey([1; 32]));
+    }
+}
@@ -1 +1,10 @@
+pub mod audit;
+pub mod db;
+#[cfg(feature = "encrypted-key")]
+pub mod encrypted_key;
+pub mod intern;
+pub mod page;
+pub mod ratelimit;
+pub mod replay;
+pub mod room;
 pub mod types;
@@ -1,19 +1,25 @@
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::Infallible;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
 
 use anyhow::{ensure, Context, Result};
+use axum::body::Bytes;
 use axum::extract::{FromRequest, FromRequestParts, Path, Query, Request, State};
 use axum::http::{header, request, StatusCode};
 use axum::response::{sse, IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::{async_trait, Json, Router};
+use blah::db;
+use blah::page::Pagination;
+use blah::ratelimit::RateLimiter;
+use blah::room::check_create_room;
 use blah::types::{
-    AuthPayload, ChatItem, ChatPayload, CreateRoomPayload, MemberPermission, RoomAttrs,
-    ServerPermission, Signee, UserKey, WithSig,
+    check_json_depth, AuthPayload, BlockPayload, ChatItem, ChatPayload, CreateRoomPayload,
+    MemberPermission, PinPayload, ProtocolLimits, RoomAttrs, ServerPermission, Signee, StoredItem,
+    UserKey, Validate, WithSig,
 };
 use ed25519_dalek::SIGNATURE_LENGTH;
 use rusqlite::{named_params, params, OptionalExtension, Row};
@@ -23,9 +29,7 @@ use tokio::sync::broadcast;
 use tokio_stream::StreamExt;
 use uuid::Uuid;
 
-const PAGE_LEN: usize = 64;
 const EVENT_QUEUE_LEN: usize = 1024;
-const MAX_BODY_LEN: usize = 4 << 10; // 4KiB
 
 #[derive(Debug, clap::Parser)]
 struct Cli {
@@ -48,7 +52,8 @@ fn main() -> Result<()> {
     let cli = <Cli as clap::Parser>::parse();
 
     let db = rusqlite::Connection::open(&cli.database).context("failed to open database")?;
-    let st = AppState::init(&*cli.base_url, db).context("failed to initialize state")?;
+    let st = AppState::init(&*cli.base_url, db, ProtocolLimits::default())
+        .context("failed to initialize state")?;
 
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -62,26 +67,32 @@ fn main() -> Result<()> {
 struct AppState {
     conn: Mutex<rusqlite::Connection>,
     room_listeners: Mutex<HashMap<u64, broadcast::Sender<Arc<ChatItem>>>>,
+    post_limiter: Mutex<RateLimiter>,
 
     base_url: Box<str>,
+    /// The single source of protocol limits of this server.
+    limits: ProtocolLimits,
 }
 
 impl AppState {
-    fn init(base_url: impl Into<Box<str>>, conn: rusqlite::Connection) -> Result<Self> {
-        static INIT_SQL: &str = include_str!("../init.sql");
-
+    fn init(
+        base_url: impl Into<Box<str>>,
+        conn: rusqlite::Connection,
+        limits: ProtocolLimits,
+    ) -> Result<Self> {
         let base_url = base_url.into();
         ensure!(
             !base_url.ends_with('/'),
             "base_url must not has trailing slash",
         );
 
-        conn.execute_batch(INIT_SQL)
-            .context("failed to initialize database")?;
+        db::init(&conn)?;
         Ok(Self {
             conn: Mutex::new(conn),
             room_listeners: Mutex::new(HashMap::new()),
+            post_limiter: Mutex::new(RateLimiter::new(&limits)),
             base_url,
+            limits,
         })
     }
 }
@@ -89,14 +100,17 @@ impl AppState {
 type ArcState = State<Arc<AppState>>;
 
 async fn main_async(opt: Cli, st: AppState) -> Result<()> {
+    let max_body_len = st.limits.max_frame_len;
     let app = Router::new()
         .route("/room/create", post(room_create))
+        .route("/user/block", post(user_block))
         // NB. Sync with `feed_url` and `next_url` generation.
         .route("/room/:ruuid/feed.json", get(room_get_feed))
         .route("/room/:ruuid/event", get(room_event))
         .route("/room/:ruuid/item", get(room_get_item).post(room_post_item))
+        .route("/room/:ruuid/pin", post(room_pin))
         .with_state(Arc::new(st))
-        .layer(tower_http::limit::RequestBodyLimitLayer::new(MAX_BODY_LEN))
+        .layer(tower_http::limit::RequestBodyLimitLayer::new(max_body_len))
         // NB. This comes at last (outmost layer), so inner errors will still be wraped with
         // correct CORS headers.
         .layer(tower_http::cors::CorsLayer::permissive());
@@ -128,11 +142,8 @@ async fn room_create(
     st: ArcState,
     SignedJson(params): SignedJson<CreateRoomPayload>,
 ) -> Result<Json<Uuid>, StatusCode> {
-    let members = &params.signee.payload.members.0;
-    if !members
-        .iter()
-        .any(|m| m.user == params.signee.user && m.permission == MemberPermission::ALL)
-    {
+    // The signature is already verified by `SignedJson`.
+    if check_create_room(&params.signee.payload, &params.signee.user, &st.limits).is_err() {
         return Err(StatusCode::BAD_REQUEST);
     }
 
@@ -187,7 +198,7 @@ async fn room_create(
             WHERE `userkey` = :userkey
             ",
         )?;
-        for member in members {
+        for member in &params.signee.payload.members.0 {
             insert_user.execute(params![member.user])?;
             insert_member.execute(named_params! {
                 ":rid": rid,
@@ -205,22 +216,15 @@ async fn room_create(
     Ok(Json(ruuid))
 }
 
-// NB. `next_url` generation depends on this structure.
-#[derive(Debug, Deserialize)]
-struct GetRoomItemParams {
-    #[serde(
-        default,
-        deserialize_with = "serde_aux::field_attributes::deserialize_number_from_string"
-    )]
-    before_id: u64,
-}
-
 async fn room_get_item(
     st: ArcState,
     Path(ruuid): Path<Uuid>,
-    params: Query<GetRoomItemParams>,
+    Query(params): Query<Pagination>,
     OptionalAuth(user): OptionalAuth,
 ) -> Result<impl IntoResponse, StatusCode> {
+    if params.validate().is_err() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
     let (room_meta, items) =
         query_room_items(&st.conn.lock().unwrap(), ruuid, user.as_ref(), &params)
             .map_err(from_db_error)?;
@@ -233,8 +237,11 @@ async fn room_get_item(
 async fn room_get_feed(
     st: ArcState,
     Path(ruuid): Path<Uuid>,
-    params: Query<GetRoomItemParams>,
+    Query(params): Query<Pagination>,
 ) -> Result<impl IntoResponse, StatusCode> {
+    if params.validate().is_err() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
     let (room_meta, items) =
         query_room_items(&st.conn.lock().unwrap(), ruuid, None, &params).map_err(from_db_error)?;
 
@@ -254,6 +261,7 @@ async fn room_get_feed(
                     timestamp: item.signee.timestamp,
                     nonce: item.signee.nonce,
                     sig: item.sig,
+                    version: item.signee.version,
                 },
             }
         })
@@ -261,9 +269,15 @@ async fn room_get_feed(
 
     let base_url = &st.base_url;
     let feed_url = format!("{base_url}/room/{ruuid}/feed.json");
-    let next_url = (items.len() == PAGE_LEN).then(|| {
-        let last_id = &items.last().expect("page size is not 0").id;
-        format!("{feed_url}?before_id={last_id}")
+    let next_url = (items.len() == params.limit).then(|| {
+        let limit = params.limit;
+        if params.after.is_some() {
+            let first_id = &items.first().expect("page size is not 0").id;
+            format!("{feed_url}?after={first_id}&limit={limit}")
+        } else {
+            let last_id = &items.last().expect("page size is not 0").id;
+            format!("{feed_url}?before={last_id}&limit={limit}")
+        }
     });
     let feed = FeedRoom {
         title: room_meta.title,
@@ -310,6 +324,7 @@ struct FeedItemExtra {
     nonce: u32,
     #[serde(with = "hex::serde")]
     sig: [u8; SIGNATURE_LENGTH],
+    version: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -349,7 +364,7 @@ fn query_room_items(
     conn: &rusqlite::Connection,
     ruuid: Uuid,
     user: Option<&UserKey>,
-    params: &GetRoomItemParams,
+    params: &Pagination,
 ) -> rusqlite::Result<(RoomMetadata, Vec<(u64, ChatItem)>)> {
     let (rid, title, attrs) = get_room_if_readable(conn, ruuid, user, |row| {
         Ok((
@@ -363,21 +378,33 @@ fn query_room_items(
 
     let mut stmt = conn.prepare(
         r"
-        SELECT `cid`, `timestamp`, `nonce`, `sig`, `userkey`, `sig`, `message`
-        FROM `room_item`
-        JOIN `user` USING (`uid`)
-        WHERE `rid` = :rid AND
-            (:before_cid = 0 OR `cid` < :before_cid)
+        SELECT * FROM (
+            SELECT `cid`, `timestamp`, `nonce`, `version`, `sig`, `userkey`, `sequence`, `message`
+            FROM `room_item`
+            JOIN `user` USING (`uid`)
+            WHERE `rid` = :rid AND
+                (:before_cid IS NULL OR `cid` < :before_cid) AND
+                (:after_cid IS NULL OR `cid` > :after_cid) AND
+                NOT EXISTS(SELECT 1
+                    FROM `user_block`
+                    JOIN `user` AS `viewer` USING (`uid`)
+                    WHERE `viewer`.`userkey` = :viewer AND
+                        `target` = `user`.`userkey`)
+            -- Pages after a cursor take the oldest items newer than it.
+            ORDER BY CASE WHEN :after_cid IS NULL THEN -`cid` ELSE `cid` END
+            LIMIT :limit
+        )
         ORDER BY `cid` DESC
-        LIMIT :limit
         ",
     )?;
     let items = stmt
         .query_and_then(
             named_params! {
                 ":rid": rid,
-                ":before_cid": params.before_id,
-                ":limit": PAGE_LEN,
+                ":after_cid": params.after.map(|key| key.0),
+                ":before_cid": params.before.map(|key| key.0),
+                ":limit": params.limit,
+                ":viewer": user,
             },
             |row| {
                 let cid = row.get::<_, u64>("cid")?;
@@ -387,8 +414,10 @@ fn query_room_items(
                         nonce: row.get("nonce")?,
                         timestamp: row.get("timestamp")?,
                         user: row.get("userkey")?,
+                        version: row.get("version")?,
                         payload: ChatPayload {
                             room: ruuid,
+                            sequence: row.get("sequence")?,
                             text: row.get("message")?,
                         },
                     },
@@ -401,19 +430,34 @@ fn query_room_items(
     Ok((room_meta, items))
 }
 
-/// Extractor for verified JSON payload.
+/// Extractor for verified JSON payload. The nesting depth is checked before parsing, per
+/// [`ProtocolLimits::max_json_depth`].
 #[derive(Debug)]
 struct SignedJson<T>(WithSig<T>);
 
 #[async_trait]
-impl<S: Send + Sync, T: Serialize + DeserializeOwned> FromRequest<S> for SignedJson<T> {
+impl<T: Serialize + DeserializeOwned> FromRequest<Arc<AppState>> for SignedJson<T> {
     type Rejection = Response;
 
-    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
-        let Json(data) = <Json<WithSig<T>> as FromRequest<S>>::from_request(req, state)
+    async fn from_request(req: Request, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        let is_json = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("application/json"));
+        if !is_json {
+            return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response());
+        }
+        let body = Bytes::from_request(req, state)
             .await
             .map_err(|err| err.into_response())?;
-        data.verify().map_err(|err| {
+        check_json_depth(&body, state.limits.max_json_depth).map_err(|err| {
+            tracing::debug!(%err, "rejected payload");
+            StatusCode::BAD_REQUEST.into_response()
+        })?;
+        let Json(data) =
+            Json::<WithSig<T>>::from_bytes(&body).map_err(|err| err.into_response())?;
+        data.verify_with(&state.limits).map_err(|err| {
             tracing::debug!(%err, "unsigned payload");
             StatusCode::BAD_REQUEST.into_response()
         })?;
@@ -426,21 +470,24 @@ impl<S: Send + Sync, T: Serialize + DeserializeOwned> FromRequest<S> for SignedJ
 struct OptionalAuth(Option<UserKey>);
 
 #[async_trait]
-impl<S: Send + Sync> FromRequestParts<S> for OptionalAuth {
+impl FromRequestParts<Arc<AppState>> for OptionalAuth {
     type Rejection = StatusCode;
 
     async fn from_request_parts(
         parts: &mut request::Parts,
-        _state: &S,
+        state: &Arc<AppState>,
     ) -> Result<Self, Self::Rejection> {
         let Some(auth) = parts.headers.get(header::AUTHORIZATION) else {
             return Ok(Self(None));
         };
 
-        let ret = serde_json::from_slice::<WithSig<AuthPayload>>(auth.as_bytes())
-            .context("invalid JSON")
+        let ret = check_json_depth(auth.as_bytes(), state.limits.max_json_depth)
+            .and_then(|()| {
+                serde_json::from_slice::<WithSig<AuthPayload>>(auth.as_bytes())
+                    .context("invalid JSON")
+            })
             .and_then(|data| {
-                data.verify()?;
+                data.verify_with(&state.limits)?;
                 Ok(data.signee.user)
             });
         match ret {
@@ -458,10 +505,13 @@ async fn room_post_item(
     Path(ruuid): Path<Uuid>,
     SignedJson(chat): SignedJson<ChatPayload>,
 ) -> Result<Json<u64>, StatusCode> {
-    if ruuid != chat.signee.payload.room {
+    if ruuid != chat.signee.payload.room || chat.signee.payload.validate(&st.limits).is_err() {
         return Err(StatusCode::BAD_REQUEST);
     }
-
+    let StoredItem {
+        item: chat,
+        received_at,
+    } = StoredItem::received_now(chat);
     let (rid, cid) = {
         let conn = st.conn.lock().unwrap();
         let Some((rid, uid)) = conn
@@ -488,12 +538,25 @@ async fn room_post_item(
             tracing::debug!("rejected post: unpermitted user {}", chat.signee.user);
             return Err(StatusCode::FORBIDDEN);
         };
+        // Only permitted posts take a token, so unknown keys cannot grow the buckets.
+        if !st
+            .post_limiter
+            .lock()
+            .unwrap()
+            .check(&chat.signee.user, received_at)
+        {
+            tracing::debug!("rate limited post from {}", chat.signee.user);
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
 
         let cid = conn
             .query_row(
                 r"
-                INSERT INTO `room_item` (`rid`, `uid`, `timestamp`, `nonce`, `sig`, `message`)
-                VALUES (:rid, :uid, :timestamp, :nonce, :sig, :message)
+                INSERT INTO `room_item`
+                    (`rid`, `uid`, `timestamp`, `nonce`, `version`, `sig`, `sequence`, `message`,
+                    `received_at`)
+                VALUES (:rid, :uid, :timestamp, :nonce, :version, :sig, :sequence, :message,
+                    :received_at)
                 RETURNING `cid`
                 ",
                 named_params! {
@@ -501,8 +564,11 @@ async fn room_post_item(
                     ":uid": uid,
                     ":timestamp": chat.signee.timestamp,
                     ":nonce": chat.signee.nonce,
+                    ":version": chat.signee.version,
+                    ":sequence": chat.signee.payload.sequence,
                     ":message": &chat.signee.payload.text,
                     ":sig": chat.sig,
+                    ":received_at": received_at,
                 },
                 |row| row.get::<_, u64>(0),
             )
@@ -523,6 +589,123 @@ async fn room_post_item(
     Ok(Json(cid))
 }
 
+async fn user_block(
+    st: ArcState,
+    SignedJson(block): SignedJson<BlockPayload>,
+) -> Result<StatusCode, StatusCode> {
+    let conn = st.conn.lock().unwrap();
+    let Some(uid) = conn
+        .query_row(
+            r"
+            SELECT `uid`
+            FROM `user`
+            WHERE `userkey` = :userkey
+            ",
+            named_params! { ":userkey": &block.signee.user },
+            |row| row.get::<_, u64>("uid"),
+        )
+        .optional()
+        .map_err(from_db_error)?
+    else {
+        return Err(StatusCode::FORBIDDEN);
+    };
+
+    let sql = if block.signee.payload.block {
+        r"
+        INSERT OR IGNORE INTO `user_block` (`uid`, `target`)
+        VALUES (:uid, :target)
+        "
+    } else {
+        r"
+        DELETE FROM `user_block`
+        WHERE `uid` = :uid AND `target` = :target
+        "
+    };
+    conn.execute(
+        sql,
+        named_params! { ":uid": uid, ":target": &block.signee.payload.target },
+    )
+    .map_err(from_db_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Get the set of users blocked by `user`.
+fn query_blocked(
+    conn: &rusqlite::Connection,
+    user: &UserKey,
+) -> rusqlite::Result<HashSet<UserKey>> {
+    let mut stmt = conn.prepare(
+        r"
+        SELECT `target`
+        FROM `user_block`
+        JOIN `user` USING (`uid`)
+        WHERE `userkey` = :userkey
+        ",
+    )?;
+    let mut blocked = HashSet::new();
+    for target in stmt.query_map(named_params! { ":userkey": user }, |row| row.get("target"))? {
+        BlockPayload {
+            block: true,
+            // Not validated, since targets are only compared against.
+            target: UserKey(target?),
+        }
+        .apply(&mut blocked);
+    }
+    Ok(blocked)
+}
+
+async fn room_pin(
+    st: ArcState,
+    Path(ruuid): Path<Uuid>,
+    SignedJson(pin): SignedJson<PinPayload>,
+) -> Result<StatusCode, StatusCode> {
+    if ruuid != pin.signee.payload.room {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let conn = st.conn.lock().unwrap();
+    let Some(rid) = conn
+        .query_row(
+            r"
+            SELECT `rid`
+            FROM `room`
+            JOIN `room_member` USING (`rid`)
+            JOIN `user` USING (`uid`)
+            WHERE `ruuid` = :ruuid AND
+                `userkey` = :userkey AND
+                (`room_member`.`permission` & :perm) = :perm
+            ",
+            named_params! {
+                ":ruuid": ruuid,
+                ":userkey": &pin.signee.user,
+                ":perm": MemberPermission::UPDATE_ROOM,
+            },
+            |row| row.get::<_, u64>("rid"),
+        )
+        .optional()
+        .map_err(from_db_error)?
+    else {
+        tracing::debug!("rejected pin: unpermitted user {}", pin.signee.user);
+        return Err(StatusCode::FORBIDDEN);
+    };
+
+    let target = &pin.signee.payload.target.0;
+    let sql = if pin.signee.payload.pin {
+        r"
+        INSERT OR IGNORE INTO `room_pin` (`rid`, `target`)
+        VALUES (:rid, :target)
+        "
+    } else {
+        r"
+        DELETE FROM `room_pin`
+        WHERE `rid` = :rid AND `target` = :target
+        "
+    };
+    conn.execute(sql, named_params! { ":rid": rid, ":target": target })
+        .map_err(from_db_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
 async fn room_event(
     st: ArcState,
     Path(ruuid): Path<Uuid>,
@@ -531,10 +714,16 @@ async fn room_event(
     // So just only support public room for now.
     OptionalAuth(user): OptionalAuth,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let rid = get_room_if_readable(&st.conn.lock().unwrap(), ruuid, user.as_ref(), |row| {
-        row.get::<_, u64>(0)
-    })
-    .map_err(from_db_error)?;
+    let (rid, blocked) = {
+        let conn = st.conn.lock().unwrap();
+        let rid = get_room_if_readable(&conn, ruuid, user.as_ref(), |row| row.get::<_, u64>(0))
+            .map_err(from_db_error)?;
+        let blocked = match &user {
+            Some(user) => query_blocked(&conn, user).map_err(from_db_error)?,
+            None => HashSet::new(),
+        };
+        (rid, blocked)
+    };
 
     let rx = match st.room_listeners.lock().unwrap().entry(rid) {
         Entry::Occupied(ent) => ent.get().subscribe(),
@@ -568,6 +757,9 @@ async fn room_event(
         let _guard = &_guard;
         // On stream closure or lagging, close the current stream so client can retry.
         let item = ret.ok()?;
+        if blocked.contains(&item.signee.user) {
+            return None;
+        }
         let evt = sse::Event::default()
             .json_data(&*item)
             .expect("serialization cannot fail");
@@ -0,0 +1,192 @@
+//! Cursor-based pagination over room items.
+//!
+//! Items are paged in descending order of their [`OrderKey`], matching the `before` cursor of
+//! the `/room/:ruuid/item` and `feed.json` endpoints.
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+
+/// The stable ordering key of a room item.
+///
+/// Keys are assigned on insertion and are never reused or reassigned. In particular, a deleted
+/// (tombstoned) item keeps its key and its slot in the ordering, so a cursor referencing any key
+/// stays valid across deletions and never skips or repeats neighbors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct OrderKey(pub u64);
+
+/// A stored room item as seen by pagination.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ItemView<T> {
+    /// Whether this item is a tombstone. The content of a deleted item must not be shown, but the
+    /// item itself is still returned to keep cursors consistent.
+    pub deleted: bool,
+    pub item: T,
+    pub key: OrderKey,
+}
+
+/// A page request. At most one of `after` and `before` can be set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pagination {
+    /// Only include items newer than the given key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<OrderKey>,
+    /// Only include items older than the given key. It is also accepted as `before_id`.
+    #[serde(default, alias = "before_id", skip_serializing_if = "Option::is_none")]
+    pub before: Option<OrderKey>,
+    #[serde(default = "Pagination::default_limit")]
+    pub limit: usize,
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Self {
+            after: None,
+            before: None,
+            limit: Self::DEFAULT_LIMIT,
+        }
+    }
+}
+
+impl Pagination {
+    pub const DEFAULT_LIMIT: usize = 64;
+    pub const MAX_LIMIT: usize = 1024;
+
+    fn default_limit() -> usize {
+        Self::DEFAULT_LIMIT
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.after.is_none() || self.before.is_none(),
+            "both `after` and `before` are set",
+        );
+        ensure!(self.limit != 0, "zero limit");
+        ensure!(
+            self.limit <= Self::MAX_LIMIT,
+            "limit exceeds {}",
+            Self::MAX_LIMIT,
+        );
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<ItemView<T>>,
+    /// The cursor for the next page in the same direction, if this page is full. It should be
+    /// passed as `after` for pages requested with `after`, or as `before` otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<OrderKey>,
+}
+
+impl<T: Clone> Page<T> {
+    /// Validate the page request and take the page from `items`, which are sorted by
+    /// [`OrderKey`] in descending order. The page is also in descending order, including
+    /// tombstones.
+    pub fn from_sorted(items: &[ItemView<T>], req: &Pagination) -> Result<Self> {
+        req.validate()?;
+        let limit = req.limit;
+        let (page, next) = if let Some(after) = req.after {
+            // The oldest `limit` items newer than `after`.
+            let end = items.partition_point(|item| item.key > after);
+            let page = &items[end.saturating_sub(limit)..end];
+            (page, page.first())
+        } else {
+            let start = match req.before {
+                Some(before) => items.partition_point(|item| item.key >= before),
+                None => 0,
+            };
+            let page = &items[start..(start + limit).min(items.len())];
+            (page, page.last())
+        };
+        let next = (page.len() == limit).then(|| next.unwrap().key);
+        Ok(Self {
+            items: page.to_vec(),
+            next,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paginate_across_tombstone() {
+        let items = (1..=5)
+            .rev()
+            .map(|i| ItemView {
+                deleted: i == 3,
+                item: i,
+                key: OrderKey(i),
+            })
+            .collect::<Vec<_>>();
+
+        let mut seen = Vec::new();
+        let mut req = Pagination {
+            limit: 2,
+            ..Pagination::default()
+        };
+        loop {
+            let page = Page::from_sorted(&items, &req).unwrap();
+            seen.extend(page.items.iter().map(|item| (item.key.0, item.deleted)));
+            match page.next {
+                Some(next) => req.before = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(
+            seen,
+            [(5, false), (4, false), (3, true), (2, false), (1, false)]
+        );
+    }
+
+    #[test]
+    fn pagination_validate() {
+        let items = (1..=5)
+            .rev()
+            .map(|i| ItemView {
+                deleted: false,
+                item: (),
+                key: OrderKey(i),
+            })
+            .collect::<Vec<_>>();
+        let keys = |page: Page<()>| page.items.iter().map(|i| i.key.0).collect::<Vec<_>>();
+
+        let req = Pagination {
+            after: Some(OrderKey(1)),
+            before: None,
+            limit: 2,
+        };
+        let page = Page::from_sorted(&items, &req).unwrap();
+        assert_eq!(page.next, Some(OrderKey(3)));
+        assert_eq!(keys(page), [3, 2]);
+        let req = Pagination {
+            after: Some(OrderKey(3)),
+            ..req
+        };
+        let page = Page::from_sorted(&items, &req).unwrap();
+        assert_eq!(page.next, Some(OrderKey(5)));
+        assert_eq!(keys(page), [5, 4]);
+
+        let invalid = [
+            Pagination {
+                after: Some(OrderKey(1)),
+                before: Some(OrderKey(3)),
+                limit: 2,
+            },
+            Pagination {
+                limit: 0,
+                ..Pagination::default()
+            },
+            Pagination {
+                limit: Pagination::MAX_LIMIT + 1,
+                ..Pagination::default()
+            },
+        ];
+        for req in invalid {
+            req.validate().unwrap_err();
+            Page::from_sorted(&items, &req).unwrap_err();
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! Per-user rate limiting of posting, as a spam control primitive for servers.
+use std::collections::HashMap;
+
+use crate::types::{ProtocolLimits, UserKey};
+
+/// A token bucket holding up to `burst` tokens, refilled by one token per `refill_secs`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenBucket {
+    /// The time (in seconds since UNIX epoch) of the last refill, with the remainder of a partial
+    /// token carried over.
+    pub last_refill: u64,
+    pub tokens: u32,
+}
+
+/// Token buckets of each user, configured by [`ProtocolLimits::rate_burst`] and
+/// [`ProtocolLimits::rate_refill_secs`].
+///
+/// Users start with a full bucket, so a new user may post `rate_burst` items at once. A full
+/// bucket is no different from a missing one, so they are evicted as the map grows, keeping the
+/// memory bounded by the users active in the last `rate_burst * rate_refill_secs` seconds.
+#[derive(Debug)]
+pub struct RateLimiter {
+    pub buckets: HashMap<UserKey, TokenBucket>,
+    burst: u32,
+    refill_secs: u64,
+    /// Evict full buckets once the map grows to this size.
+    prune_at: usize,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &ProtocolLimits) -> Self {
+        Self {
+            buckets: HashMap::new(),
+            burst: limits.rate_burst,
+            // Zero would never refill, which is not what anyone wants.
+            refill_secs: limits.rate_refill_secs.max(1),
+            prune_at: Self::MIN_PRUNE_AT,
+        }
+    }
+
+    const MIN_PRUNE_AT: usize = 1024;
+
+    /// Evict buckets that are refilled to full at `now`.
+    pub fn prune(&mut self, now: u64) {
+        let (burst, refill_secs) = (u64::from(self.burst), self.refill_secs);
+        self.buckets.retain(|_, bucket| {
+            let refilled = now.saturating_sub(bucket.last_refill) / refill_secs;
+            u64::from(bucket.tokens).saturating_add(refilled) < burst
+        });
+        // Amortize the cost over insertions.
+        self.prune_at = (self.buckets.len() * 2).max(Self::MIN_PRUNE_AT);
+    }
+
+    /// Take a token of `user` at `now` (in seconds since UNIX epoch). Returns false if there is
+    /// none left, ie. the action should be rejected.
+    pub fn check(&mut self, user: &UserKey, now: u64) -> bool {
+        if self.buckets.len() >= self.prune_at {
+            self.prune(now);
+        }
+        let (burst, refill_secs) = (self.burst, self.refill_secs);
+        let bucket = self
+            .buckets
+            .entry(user.clone())
+            .or_insert_with(|| TokenBucket {
+                last_refill: now,
+                tokens: burst,
+            });
+
+        let refilled = now.saturating_sub(bucket.last_refill) / refill_secs;
+        if refilled > 0 {
+            let tokens = u64::from(bucket.tokens).saturating_add(refilled);
+            if tokens >= u64::from(burst) {
+                bucket.tokens = burst;
+                bucket.last_refill = now;
+            } else {
+                bucket.tokens = tokens as u32;
+                bucket.last_refill += refilled * refill_secs;
+            }
+        }
+
+        if bucket.tokens == 0 {
+            return false;
+        }
+        bucket.tokens -= 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let limits = ProtocolLimits {
+            rate_burst: 3,
+            rate_refill_secs: 10,
+            ..ProtocolLimits::default()
+        };
+        let mut limiter = RateLimiter::new(&limits);
+        let (alice, bob) = (UserKey([1; 32]), UserKey([2; 32]));
+
+        // Burst.
+        assert!((0..3).all(|_| limiter.check(&alice, 100)));
+        assert!(!limiter.check(&alice, 100));
+        // Independent per user.
+        assert!(limiter.check(&bob, 100));
+
+        // One token per 10s, with the partial progress kept.
+        assert!(!limiter.check(&alice, 109));
+        assert!(limiter.check(&alice, 115));
+        assert!(!limiter.check(&alice, 115));
+        assert!(limiter.check(&alice, 120));
+        assert!(!limiter.check(&alice, 120));
+
+        // Refill is capped at the burst.
+        assert!((0..3).all(|_| limiter.check(&alice, 10_000)));
+        assert!(!limiter.check(&alice, 10_000));
+    }
+
+    #[test]
+    fn prune_full_buckets() {
+        let limits = ProtocolLimits {
+            rate_burst: 3,
+            rate_refill_secs: 10,
+            ..ProtocolLimits::default()
+        };
+        let mut limiter = RateLimiter::new(&limits);
+        // Fresh keys on every request.
+        for i in 0..10_000u32 {
+            let mut key = [0; 32];
+            key[..4].copy_from_slice(&i.to_le_bytes());
+            assert!(limiter.check(&UserKey(key), 100 + u64::from(i)));
+        }
+        assert!(limiter.buckets.len() < 2 * RateLimiter::MIN_PRUNE_AT);
+
+        let alice = UserKey([1; 32]);
+        assert!((0..3).all(|_| limiter.check(&alice, 20_000)));
+        // Evicting is lossless: a drained bucket is kept, and a full one behaves as new.
+        limiter.prune(20_000);
+        assert_eq!(limiter.buckets.len(), 1);
+        assert!(!limiter.check(&alice, 20_000));
+        limiter.prune(20_030);
+        assert!(limiter.buckets.is_empty());
+        assert!((0..3).all(|_| limiter.check(&alice, 20_030)));
+        assert!(!limiter.check(&alice, 20_030));
+    }
+}
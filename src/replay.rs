@@ -0,0 +1,100 @@
+//! Replay protection for signed items.
+use std::collections::{BTreeSet, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::types::{Signee, UserKey};
+
+/// The identity of a signed item for replay detection.
+///
+/// Signed items with different timestamps but the same nonce from the same user are considered
+/// replays, since the nonce is chosen randomly for each item.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct SeenKey {
+    pub nonce: u32,
+    pub user: UserKey,
+}
+
+impl<T> From<&Signee<T>> for SeenKey {
+    fn from(signee: &Signee<T>) -> Self {
+        Self {
+            nonce: signee.nonce,
+            user: signee.user.clone(),
+        }
+    }
+}
+
+/// The set of recently seen items within the timestamp tolerance window.
+///
+/// Items older than the window are rejected by timestamp checks anyway, so they are forgotten
+/// automatically to keep the storage bounded.
+#[derive(Debug)]
+pub struct ReplayWindow {
+    by_time: BTreeSet<(u64, SeenKey)>,
+    seen: HashSet<SeenKey>,
+    tolerance: u64,
+}
+
+impl ReplayWindow {
+    pub fn new(tolerance: u64) -> Self {
+        Self {
+            by_time: BTreeSet::new(),
+            seen: HashSet::new(),
+            tolerance,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    /// Forget all items which are stale at time `now`.
+    pub fn expire(&mut self, now: u64) {
+        while let Some((timestamp, _)) = self.by_time.first() {
+            if timestamp.saturating_add(self.tolerance) > now {
+                break;
+            }
+            let (_, key) = self.by_time.pop_first().unwrap();
+            self.seen.remove(&key);
+        }
+    }
+
+    /// Record an item with signed `timestamp` at time `now`.
+    /// Return `false` if it is a replay of an item in the window.
+    pub fn insert(&mut self, key: SeenKey, timestamp: u64, now: u64) -> bool {
+        self.expire(now);
+        if !self.seen.insert(key.clone()) {
+            return false;
+        }
+        self.by_time.insert((timestamp, key));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_window() {
+        let key = |nonce: u32| SeenKey {
+            nonce,
+            user: UserKey([1; 32]),
+        };
+        let mut window = ReplayWindow::new(90);
+        assert!(window.insert(key(1), 1000, 1000));
+        assert!(window.insert(key(2), 1010, 1010));
+        assert!(!window.insert(key(1), 1050, 1050));
+        assert_eq!(window.len(), 2);
+
+        window.expire(1090);
+        assert_eq!(window.len(), 1);
+        assert!(window.insert(key(1), 1090, 1090));
+        window.expire(1200);
+        assert!(window.is_empty());
+    }
+}
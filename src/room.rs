@@ -0,0 +1,1658 @@
+//! Server-side room state helpers built on top of the wire types in [`crate::types`].
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::types::{
+    AnyPayload, ChatItem, CreateRoomPayload, InviteToken, MemberPermission, MessageId,
+    ProtocolLimits, RoomAdminPayload, RoomAlias, RoomAttrs, RoomMember, RoomMemberList, UserKey,
+    Validate, VerifyError, VerifyOptions, WithSig,
+};
+
+/// The mapping from room aliases to room ids.
+///
+/// Each alias refers to at most one room, while a room may have multiple aliases.
+#[derive(Debug, Default)]
+pub struct AliasMap(HashMap<RoomAlias, Uuid>);
+
+impl AliasMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, alias: &RoomAlias) -> Option<Uuid> {
+        self.0.get(alias).copied()
+    }
+
+    /// Bind `alias` to `room`. Fails if the alias is already taken by another room.
+    pub fn insert(&mut self, alias: RoomAlias, room: Uuid) -> Result<()> {
+        match self.0.entry(alias) {
+            Entry::Occupied(ent) if *ent.get() != room => {
+                bail!("alias {} is already used by another room", ent.key())
+            }
+            Entry::Occupied(_) => {}
+            Entry::Vacant(ent) => {
+                ent.insert(room);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, alias: &RoomAlias) -> Option<Uuid> {
+        self.0.remove(alias)
+    }
+}
+
+/// The version of a room member list, increased on every membership change.
+///
+/// Clients caching member lists can compare versions to decide whether to refetch.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct MembershipVersion(pub u64);
+
+/// The current state of a room.
+#[derive(Debug, Clone)]
+pub struct RoomSnapshot {
+    pub attrs: RoomAttrs,
+    /// The room description. See [`RoomAdminPayload::SetDescription`].
+    pub description: Option<String>,
+    pub members: RoomMemberList,
+    pub members_version: MembershipVersion,
+    /// Chat items with signed timestamps before this are cleared. Zero if never cleared.
+    pub history_watermark: u64,
+    pub title: String,
+}
+
+impl From<CreateRoomPayload> for RoomSnapshot {
+    fn from(payload: CreateRoomPayload) -> Self {
+        Self {
+            attrs: payload.attrs,
+            description: None,
+            members: payload.members,
+            members_version: MembershipVersion::default(),
+            history_watermark: 0,
+            title: payload.title,
+        }
+    }
+}
+
+impl RoomSnapshot {
+    /// Validate under `limits` and apply an admin operation from `signer`, who must be a member
+    /// with sufficient permission. The signature is not checked here.
+    ///
+    /// Added members must fit in [`ProtocolLimits::max_members`], see [`apply_add_member`].
+    ///
+    /// `members_version` is increased if the member list is changed. Rejected operations and
+    /// operations not about membership leave it unchanged.
+    pub fn apply_admin(
+        &mut self,
+        signer: &UserKey,
+        op: &RoomAdminPayload,
+        limits: &ProtocolLimits,
+    ) -> Result<()> {
+        op.validate(limits)?;
+        check_authorized(&self.members, signer, op)?;
+        self.apply_authorized(op, limits)
+    }
+
+    fn apply_authorized(&mut self, op: &RoomAdminPayload, limits: &ProtocolLimits) -> Result<()> {
+        match op {
+            RoomAdminPayload::Batch { ops, .. } => {
+                let mut next = self.clone();
+                for (i, op) in ops.0.iter().enumerate() {
+                    next.apply_authorized(op, limits)
+                        .with_context(|| format!("invalid batched operation #{i}"))?;
+                }
+                *self = next;
+            }
+            RoomAdminPayload::AddMember { .. } | RoomAdminPayload::RemoveMember { .. } => {
+                apply_member_op(&mut self.members, op, limits)?;
+                self.members_version.0 += 1;
+            }
+            RoomAdminPayload::SetTitle { title, .. } => self.title.clone_from(title),
+            RoomAdminPayload::UpdateRoomAttrs { attrs, .. } => self.attrs = *attrs,
+            RoomAdminPayload::SetDescription { description, .. } => {
+                self.description = (!description.is_empty()).then(|| description.clone());
+            }
+            RoomAdminPayload::ClearHistory { up_to, .. } => {
+                self.history_watermark = self.history_watermark.max(*up_to);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove `signer` from the room on their own [`LeavePayload`](crate::types::LeavePayload).
+    /// The signature is not checked here.
+    pub fn apply_leave(&mut self, signer: &UserKey) -> Result<()> {
+        self.members.remove(signer)?;
+        self.members_version.0 += 1;
+        Ok(())
+    }
+}
+
+/// The UUIDv5 namespace of [`dm_room_id`].
+// NB. This must never change, or existing direct messages would be split.
+const DM_ROOM_NAMESPACE: Uuid = Uuid::from_u128(0x94c6865f_e77c_4e28_8081_81242c22db06);
+
+/// The stable room id of direct messages between `a` and `b`, which is the same no matter who
+/// initiates.
+///
+/// It is the UUIDv5 of both keys concatenated in sorted order.
+pub fn dm_room_id(a: &UserKey, b: &UserKey) -> Uuid {
+    let (lo, hi) = if a <= b { (a, b) } else { (b, a) };
+    let mut name = [0u8; 64];
+    name[..32].copy_from_slice(&lo.0);
+    name[32..].copy_from_slice(&hi.0);
+    Uuid::new_v5(&DM_ROOM_NAMESPACE, &name)
+}
+
+/// Verify a signed room creation: the signature and timestamp under `opts`, and the payload
+/// like [`check_create_room`].
+pub fn verify_create_room(signed: &WithSig<CreateRoomPayload>, opts: &VerifyOptions) -> Result<()> {
+    signed.verify_with_options(opts)?;
+    check_create_room(&signed.signee.payload, &signed.signee.user, &opts.limits)
+}
+
+/// Check a room creation `payload` from `creator` without the signature, eg. if it is already
+/// verified: the payload is validated under `limits`, and the creator must be a member with all
+/// permissions.
+pub fn check_create_room(
+    payload: &CreateRoomPayload,
+    creator: &UserKey,
+    limits: &ProtocolLimits,
+) -> Result<()> {
+    payload.validate(limits)?;
+    payload.check_creator(creator)?;
+    Ok(())
+}
+
+/// Verify each of signed room creations `payloads` independently like [`verify_create_room`],
+/// eg. for bulk migration from another system, and report results in the same order.
+///
+/// Each accepted creation is assigned a new random room id, like the server does on creation.
+pub fn import_rooms(
+    payloads: Vec<WithSig<CreateRoomPayload>>,
+    opts: &VerifyOptions,
+) -> Vec<Result<Uuid, VerifyError>> {
+    payloads
+        .iter()
+        .map(|signed| {
+            signed.verify_with_options(opts)?;
+            let payload = &signed.signee.payload;
+            payload
+                .validate(&opts.limits)
+                .and_then(|()| Ok(payload.check_creator(&signed.signee.user)?))
+                .map_err(VerifyError::InvalidPayload)?;
+            Ok(Uuid::new_v4())
+        })
+        .collect()
+}
+
+/// Verify a signed chat under `opts`, and that the author is a member of `members` with
+/// [`MemberPermission::POST_CHAT`].
+pub fn verify_chat(item: &ChatItem, members: &RoomMemberList, opts: &VerifyOptions) -> Result<()> {
+    item.verify_with_options(opts)?;
+    let author = &item.signee.user;
+    let member = members
+        .get(author)
+        .with_context(|| format!("author {author} is not a member"))?;
+    ensure!(
+        member.permission.contains(MemberPermission::POST_CHAT),
+        "author {author} has no permission to post",
+    );
+    Ok(())
+}
+
+/// Redeem an invite token for `joiner` at `now` (in seconds since UNIX epoch), returning the
+/// member to add.
+///
+/// Only the signature and expiry are checked here. Tokens are long-lived, so the signing
+/// timestamp is not. The caller must still check the signer has
+/// [`MemberPermission::ADD_MEMBER`] in the room.
+pub fn redeem(token: &WithSig<InviteToken>, joiner: &UserKey, now: u64) -> Result<RoomMember> {
+    token.verify_signature()?;
+    let payload = &token.signee.payload;
+    ensure!(now < payload.expires_at, "invite token expired");
+    Ok(RoomMember {
+        permission: payload.permission,
+        user: joiner.clone(),
+    })
+}
+
+/// Check `signer` may pin or unpin messages, ie. has [`MemberPermission::UPDATE_ROOM`].
+pub fn check_pin_authorized(list: &RoomMemberList, signer: &UserKey) -> Result<()> {
+    let member = list
+        .get(signer)
+        .with_context(|| format!("signer {signer} is not a member"))?;
+    ensure!(
+        member.permission.contains(MemberPermission::UPDATE_ROOM),
+        "signer {signer} has no permission to pin",
+    );
+    Ok(())
+}
+
+/// Check if an admin operation is authorized for a signer with permission `signer_perm`.
+///
+/// This is the single source of the admin operation policy. A batch is authorized if all its
+/// operations are, judged by the permission before the batch. Members can only grant a subset of
+/// their own permission, see [`EscalationError`].
+pub fn is_admin_op_authorized(op: &RoomAdminPayload, signer_perm: MemberPermission) -> bool {
+    if find_escalation(op, signer_perm).is_some() {
+        return false;
+    }
+    let required = match op {
+        RoomAdminPayload::Batch { ops, .. } => {
+            return ops
+                .0
+                .iter()
+                .all(|op| is_admin_op_authorized(op, signer_perm))
+        }
+        RoomAdminPayload::AddMember { .. } => MemberPermission::ADD_MEMBER,
+        RoomAdminPayload::RemoveMember { .. } => MemberPermission::REMOVE_MEMBER,
+        RoomAdminPayload::SetTitle { .. }
+        | RoomAdminPayload::SetDescription { .. }
+        | RoomAdminPayload::ClearHistory { .. }
+        | RoomAdminPayload::UpdateRoomAttrs { .. } => MemberPermission::UPDATE_ROOM,
+    };
+    signer_perm.contains(required)
+}
+
+/// Check each operation of a batch to be sent locally, with the same policy as
+/// [`is_admin_op_authorized`], to avoid a round-trip rejection. The results are in the same
+/// order as `ops`.
+pub fn preflight_batch(
+    ops: &[RoomAdminPayload],
+    signer_perm: MemberPermission,
+) -> Vec<Result<(), String>> {
+    ops.iter()
+        .map(|op| {
+            if is_admin_op_authorized(op, signer_perm) {
+                Ok(())
+            } else {
+                Err(format!("not authorized with permission {signer_perm}"))
+            }
+        })
+        .collect()
+}
+
+/// An `AddMember` operation granting permissions the signer does not hold, which is a privilege
+/// escalation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EscalationError {
+    pub granted: MemberPermission,
+    pub held: MemberPermission,
+}
+
+impl fmt::Display for EscalationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cannot grant permission {} beyond own permission {}",
+            self.granted, self.held,
+        )
+    }
+}
+
+impl std::error::Error for EscalationError {}
+
+/// Find the first `AddMember`, possibly inside a batch, granting more than `signer_perm`.
+fn find_escalation(
+    op: &RoomAdminPayload,
+    signer_perm: MemberPermission,
+) -> Option<EscalationError> {
+    match op {
+        RoomAdminPayload::Batch { ops, .. } => {
+            ops.0.iter().find_map(|op| find_escalation(op, signer_perm))
+        }
+        RoomAdminPayload::AddMember { permission, .. } if !signer_perm.contains(*permission) => {
+            Some(EscalationError {
+                granted: *permission,
+                held: signer_perm,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn check_authorized(list: &RoomMemberList, signer: &UserKey, op: &RoomAdminPayload) -> Result<()> {
+    let signer_perm = list
+        .get(signer)
+        .with_context(|| format!("signer {signer} is not a member"))?
+        .permission;
+    if let Some(err) = find_escalation(op, signer_perm) {
+        return Err(err.into());
+    }
+    ensure!(
+        is_admin_op_authorized(op, signer_perm),
+        "signer {signer} has no permission for this operation",
+    );
+    Ok(())
+}
+
+/// The number of admins in the room, ie. members with [`MemberPermission::ADD_MEMBER`] or
+/// [`MemberPermission::REMOVE_MEMBER`].
+pub fn admin_quorum(list: &RoomMemberList) -> usize {
+    list.0.iter().filter(|m| is_admin(m)).count()
+}
+
+fn is_admin(member: &RoomMember) -> bool {
+    member
+        .permission
+        .intersects(MemberPermission::ADD_MEMBER | MemberPermission::REMOVE_MEMBER)
+}
+
+/// Check at least `threshold` distinct admins of the room are in `signers`, for operations
+/// requiring multiple admins, eg. 2-of-3. Non-admin signers are ignored.
+///
+/// Fails early if the room does not even have `threshold` admins.
+pub fn check_quorum(list: &RoomMemberList, signers: &[UserKey], threshold: usize) -> Result<()> {
+    let quorum = admin_quorum(list);
+    ensure!(
+        threshold <= quorum,
+        "threshold {threshold} exceeds the number of admins {quorum}",
+    );
+    let mut approved = signers
+        .iter()
+        .filter(|user| list.get(user).is_some_and(is_admin))
+        .collect::<Vec<_>>();
+    approved.sort();
+    approved.dedup();
+    ensure!(
+        approved.len() >= threshold,
+        "only {} of required {threshold} admins approved",
+        approved.len(),
+    );
+    Ok(())
+}
+
+/// Apply the membership change of an admin operation, if any, keeping the room within
+/// [`ProtocolLimits::max_members`].
+fn apply_member_op(
+    list: &mut RoomMemberList,
+    op: &RoomAdminPayload,
+    limits: &ProtocolLimits,
+) -> Result<()> {
+    match op {
+        RoomAdminPayload::Batch { ops, .. } => {
+            let mut next = list.clone();
+            for op in &ops.0 {
+                apply_member_op(&mut next, op, limits)?;
+            }
+            *list = next;
+            Ok(())
+        }
+        RoomAdminPayload::AddMember {
+            permission, user, ..
+        } => apply_add_member(
+            list,
+            RoomMember {
+                permission: *permission,
+                user: user.clone(),
+            },
+            limits.max_members,
+        ),
+        RoomAdminPayload::RemoveMember { user, .. } => {
+            list.remove(user)?;
+            Ok(())
+        }
+        RoomAdminPayload::SetTitle { .. }
+        | RoomAdminPayload::SetDescription { .. }
+        | RoomAdminPayload::ClearHistory { .. }
+        | RoomAdminPayload::UpdateRoomAttrs { .. } => Ok(()),
+    }
+}
+
+/// Apply an `AddMember` operation to `list`, which must not grow beyond `max` members.
+pub fn apply_add_member(list: &mut RoomMemberList, member: RoomMember, max: usize) -> Result<()> {
+    ensure!(list.0.len() < max, "room is full ({max} members)");
+    list.insert(member)
+}
+
+/// Replay signed admin operations in order starting from the `initial` member list, and return
+/// the resulting member list, which must fit in `limits.max_members`.
+///
+/// Each operation must be correctly signed, and be authorized by a member of the room at that
+/// point with sufficient permission. Timestamps are not checked for freshness since these are
+/// historical operations. The caller is responsible to ensure all `ops` target the same room.
+pub fn replay_membership(
+    initial: RoomMemberList,
+    ops: &[WithSig<RoomAdminPayload>],
+    limits: &ProtocolLimits,
+) -> Result<RoomMemberList> {
+    let mut list = initial;
+    for (i, op) in ops.iter().enumerate() {
+        (|| {
+            op.verify_signature()?;
+            check_authorized(&list, &op.signee.user, &op.signee.payload)?;
+            apply_member_op(&mut list, &op.signee.payload, limits)
+        })()
+        .with_context(|| format!("invalid operation #{i}"))?;
+    }
+    Ok(list)
+}
+
+/// Replay signed admin operations in order on top of the room creation `create`, validated under
+/// `limits`, and return the resulting title and attributes.
+///
+/// Like [`replay_membership`], each operation must be correctly signed and authorized at that
+/// point, following [`RoomSnapshot::apply_admin`]. Membership changes are applied as well, since
+/// they affect the authorization of later operations.
+pub fn fold_room_metadata(
+    create: &CreateRoomPayload,
+    ops: &[WithSig<RoomAdminPayload>],
+    limits: &ProtocolLimits,
+) -> Result<(String, RoomAttrs)> {
+    let mut snapshot = RoomSnapshot::from(create.clone());
+    for (i, op) in ops.iter().enumerate() {
+        (|| {
+            op.verify_signature()?;
+            snapshot.apply_admin(&op.signee.user, &op.signee.payload, limits)
+        })()
+        .with_context(|| format!("invalid operation #{i}"))?;
+    }
+    Ok((snapshot.title, snapshot.attrs))
+}
+
+/// Verify the full ordered history `log` of room `room` with items validated under `limits`, and
+/// return the final state of the room.
+///
+/// The first item must be the signed room creation, and the rest must be chats, edits,
+/// deletions, pins, admin operations or leaves of this room. Each item must be correctly signed
+/// and valid, and be authorized by the membership reconstructed at that point:
+/// - Chats need [`MemberPermission::POST_CHAT`].
+/// - Edits must target a live (not deleted) chat of the same author.
+/// - Deletions must target a live chat, and be from the author or a member with
+///   [`MemberPermission::UPDATE_ROOM`].
+/// - Pins must target a live chat. See [`check_pin_authorized`].
+/// - Admin operations follow [`RoomSnapshot::apply_admin`]. Clearing the history deletes all
+///   live chats signed before the watermark, and chats appended later but signed before it are
+///   deleted right away.
+///
+/// Timestamps are not checked for freshness since these are historical items. The first failure
+/// is returned with the index of the offending item.
+pub fn verify_room_log(
+    room: Uuid,
+    log: &[WithSig<AnyPayload>],
+    limits: &ProtocolLimits,
+) -> Result<RoomSnapshot> {
+    let (first, rest) = log.split_first().context("empty room log")?;
+    let mut snapshot = (|| {
+        first.verify_signature()?;
+        let AnyPayload::CreateRoom(create) = &first.signee.payload else {
+            bail!("not a room creation");
+        };
+        create.validate(limits)?;
+        create.check_creator(&first.signee.user)?;
+        Ok(RoomSnapshot {
+            attrs: create.attrs,
+            description: None,
+            members: create.members.clone(),
+            members_version: MembershipVersion::default(),
+            history_watermark: 0,
+            title: create.title.clone(),
+        })
+    })()
+    .context("invalid log entry #0")?;
+
+    // Live chats and their authors and timestamps.
+    let mut chats = HashMap::<MessageId, (UserKey, u64)>::new();
+    let live_author = |chats: &HashMap<MessageId, (UserKey, u64)>, target: &MessageId| {
+        chats
+            .get(target)
+            .map(|(author, _)| author.clone())
+            .with_context(|| format!("target {target} does not exist or is deleted"))
+    };
+    for (i, item) in rest.iter().enumerate() {
+        (|| {
+            item.verify_signature()?;
+            let payload = &item.signee.payload;
+            payload.validate(limits)?;
+            let id = MessageId::of(item);
+            payload.validate_with_id(&id)?;
+            let signer = &item.signee.user;
+            let item_room = match payload {
+                AnyPayload::Chat(p) => p.room,
+                AnyPayload::DeleteChat(p) => p.room,
+                AnyPayload::EditChat(p) => p.room,
+                AnyPayload::Leave(p) => p.room,
+                AnyPayload::Pin(p) => p.room,
+                AnyPayload::RoomAdmin(p) => p.room(),
+                _ => bail!("unexpected item in room log"),
+            };
+            ensure!(item_room == room, "item of another room {item_room}");
+
+            match payload {
+                AnyPayload::Chat(_) => {
+                    let member = snapshot
+                        .members
+                        .get(signer)
+                        .with_context(|| format!("author {signer} is not a member"))?;
+                    ensure!(
+                        member.permission.contains(MemberPermission::POST_CHAT),
+                        "author {signer} has no permission to post",
+                    );
+                    if item.signee.timestamp >= snapshot.history_watermark {
+                        chats.insert(id, (signer.clone(), item.signee.timestamp));
+                    }
+                }
+                AnyPayload::EditChat(p) => {
+                    let author = live_author(&chats, &p.target)?;
+                    ensure!(
+                        author == *signer,
+                        "edit by {signer} of a chat from {author}"
+                    );
+                }
+                AnyPayload::DeleteChat(p) => {
+                    let author = live_author(&chats, &p.target)?;
+                    if author != *signer {
+                        let perm = snapshot
+                            .members
+                            .get(signer)
+                            .with_context(|| format!("signer {signer} is not a member"))?
+                            .permission;
+                        ensure!(
+                            perm.contains(MemberPermission::UPDATE_ROOM),
+                            "signer {signer} has no permission to delete others' chats",
+                        );
+                    }
+                    chats.remove(&p.target);
+                }
+                AnyPayload::Pin(p) => {
+                    check_pin_authorized(&snapshot.members, signer)?;
+                    live_author(&chats, &p.target)?;
+                }
+                AnyPayload::RoomAdmin(op) => {
+                    snapshot.apply_admin(signer, op, limits)?;
+                    let watermark = snapshot.history_watermark;
+                    chats.retain(|_, (_, timestamp)| *timestamp >= watermark);
+                }
+                AnyPayload::Leave(_) => snapshot.apply_leave(signer)?,
+                _ => unreachable!(),
+            }
+            Ok(())
+        })()
+        .with_context(|| format!("invalid log entry #{}", i + 1))?;
+    }
+    Ok(snapshot)
+}
+
+/// Detect the gap of missing sequence numbers before `next`, given the `last` observed sequence
+/// number of the same author in the same room, or `None` if nothing is observed yet.
+///
+/// See [`ChatPayload::sequence`](crate::types::ChatPayload::sequence). Duplicated or
+/// out-of-order sequence numbers are not gaps.
+pub fn sequence_gap(last: Option<u64>, next: u64) -> Option<Range<u64>> {
+    let expected = last.map_or(0, |last| last.saturating_add(1));
+    (next > expected).then_some(expected..next)
+}
+
+/// The highest observed sequence number of each author in a room, for detecting newly missing
+/// ranges with [`sequence_gap`].
+#[derive(Debug, Default)]
+pub struct SequenceTracker(HashMap<UserKey, u64>);
+
+impl SequenceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Observe sequence number `seq` from `user`, and return gaps newly detected by it, which
+    /// should be requested for resync.
+    ///
+    /// Duplicated or late arrivals, eg. filling an earlier gap, detect nothing.
+    pub fn observe(&mut self, user: &UserKey, seq: u64) -> Vec<Range<u64>> {
+        let last = self.0.get(user).copied();
+        if last.is_none_or(|last| last < seq) {
+            self.0.insert(user.clone(), seq);
+        }
+        sequence_gap(last, seq).into_iter().collect()
+    }
+}
+
+/// The latest receipt position of each user in a room, of
+/// [`DeliveryReceiptPayload`](crate::types::DeliveryReceiptPayload) or
+/// [`ReadReceiptPayload`](crate::types::ReadReceiptPayload).
+///
+/// Receipts only move forward: one at or before the recorded position of the same user is
+/// ignored. Positions are the order of the acknowledged items in the room, eg. their row ids,
+/// resolved from the `up_to` ids by the caller. Each kind of receipts should use its own marks.
+#[derive(Debug, Default)]
+pub struct ReceiptMarks(HashMap<UserKey, u64>);
+
+impl ReceiptMarks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, user: &UserKey) -> Option<u64> {
+        self.0.get(user).copied()
+    }
+
+    /// Record a receipt of `user` up to `position`. Returns whether it moves forward.
+    pub fn advance(&mut self, user: &UserKey, position: u64) -> bool {
+        match self.0.entry(user.clone()) {
+            Entry::Occupied(mut ent) if *ent.get() < position => {
+                ent.insert(position);
+                true
+            }
+            Entry::Occupied(_) => false,
+            Entry::Vacant(ent) => {
+                ent.insert(position);
+                true
+            }
+        }
+    }
+}
+
+/// A bloom filter over the members of a room, for fast negative membership checks.
+///
+/// [`MembershipFilter::maybe_member`] never returns `false` for an actual member, but may return
+/// `true` for a non-member with a false-positive rate of about 1%, so the caller must fall back to
+/// [`RoomMemberList::contains`] on a positive. Hash indices are taken directly from key bytes,
+/// thus crafted keys can only increase the false-positive rate, never cause false negatives.
+#[derive(Debug, Clone)]
+pub struct MembershipFilter {
+    bits: Box<[u64]>,
+}
+
+impl MembershipFilter {
+    const BITS_PER_MEMBER: usize = 10;
+    const HASHES: u64 = 7;
+
+    pub fn new(list: &RoomMemberList) -> Self {
+        let words = (list.0.len() * Self::BITS_PER_MEMBER).div_ceil(64).max(1);
+        let mut this = Self {
+            bits: vec![0u64; words].into(),
+        };
+        for m in &list.0 {
+            for idx in this.indices(&m.user) {
+                this.bits[idx / 64] |= 1 << (idx % 64);
+            }
+        }
+        this
+    }
+
+    pub fn maybe_member(&self, user: &UserKey) -> bool {
+        self.indices(user)
+            .all(|idx| self.bits[idx / 64] & (1 << (idx % 64)) != 0)
+    }
+
+    fn indices(&self, user: &UserKey) -> impl Iterator<Item = usize> {
+        // Public keys are uniformly distributed, so we use them as hashes directly, combined via
+        // double hashing.
+        let h1 = u64::from_le_bytes(user.0[..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(user.0[8..16].try_into().unwrap());
+        let nbits = self.bits.len() as u64 * 64;
+        (0..Self::HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % nbits) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+    use rand::rngs::OsRng;
+
+    use super::*;
+    use crate::types::{
+        AdminBatch, ChatPayload, DeleteChatPayload, EditChatPayload, LeavePayload, MemberListError,
+        PinPayload, UserKey,
+    };
+
+    #[test]
+    fn alias_map() {
+        let (r1, r2) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut map = AliasMap::new();
+        map.insert("general".parse().unwrap(), r1).unwrap();
+        map.insert("general".parse().unwrap(), r1).unwrap();
+        assert_eq!(map.get(&"#GENERAL".parse().unwrap()), Some(r1));
+        map.insert("General".parse().unwrap(), r2).unwrap_err();
+        assert_eq!(map.remove(&"general".parse().unwrap()), Some(r1));
+        assert_eq!(map.get(&"general".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn add_member_capacity() {
+        let member = |b: u8| RoomMember {
+            permission: MemberPermission::POST_CHAT,
+            user: UserKey([b; 32]),
+        };
+        let mut list = RoomMemberList(vec![member(1)]);
+        apply_add_member(&mut list, member(2), 2).unwrap();
+        assert_eq!(list.0.len(), 2);
+        apply_add_member(&mut list, member(3), 2).unwrap_err();
+        assert_eq!(list.0.len(), 2);
+
+        // Admin operations on a full room, single or batched.
+        let limits = ProtocolLimits {
+            max_members: 2,
+            ..ProtocolLimits::default()
+        };
+        let owner = UserKey([1; 32]);
+        let mut snapshot = RoomSnapshot::from(CreateRoomPayload {
+            attrs: RoomAttrs::empty(),
+            members: RoomMemberList(vec![RoomMember {
+                permission: MemberPermission::ALL,
+                user: owner.clone(),
+            }]),
+            title: "title".into(),
+        });
+        let add = |b: u8| RoomAdminPayload::AddMember {
+            permission: MemberPermission::POST_CHAT,
+            room: Uuid::nil(),
+            user: UserKey([b; 32]),
+        };
+        snapshot.apply_admin(&owner, &add(2), &limits).unwrap();
+        let err = snapshot.apply_admin(&owner, &add(3), &limits).unwrap_err();
+        assert!(format!("{err:#}").contains("room is full"), "{err:#}");
+
+        let mut snapshot = RoomSnapshot::from(CreateRoomPayload {
+            attrs: RoomAttrs::empty(),
+            members: RoomMemberList(vec![RoomMember {
+                permission: MemberPermission::ALL,
+                user: owner.clone(),
+            }]),
+            title: "title".into(),
+        });
+        let batch = RoomAdminPayload::Batch {
+            ops: AdminBatch(vec![add(2), add(3)]),
+            room: Uuid::nil(),
+        };
+        let err = snapshot.apply_admin(&owner, &batch, &limits).unwrap_err();
+        assert!(format!("{err:#}").contains("room is full"), "{err:#}");
+        assert_eq!(snapshot.members.0.len(), 1);
+        assert_eq!(snapshot.members_version, MembershipVersion::default());
+    }
+
+    #[test]
+    fn replay_unauthorized() {
+        let (owner, alice, bob) = (
+            SigningKey::from_bytes(&[1; 32]),
+            SigningKey::from_bytes(&[2; 32]),
+            SigningKey::from_bytes(&[3; 32]),
+        );
+        let room = Uuid::nil();
+        let key = |k: &SigningKey| UserKey(k.verifying_key().to_bytes());
+        let add = |signer: &SigningKey, user: &SigningKey, permission| {
+            let payload = RoomAdminPayload::AddMember {
+                permission,
+                room,
+                user: key(user),
+            };
+            WithSig::sign(signer, &mut OsRng, payload).unwrap()
+        };
+        let initial = || {
+            RoomMemberList(vec![RoomMember {
+                permission: MemberPermission::ALL,
+                user: key(&owner),
+            }])
+        };
+
+        let ops = [
+            add(&owner, &alice, MemberPermission::POST_CHAT),
+            add(&alice, &bob, MemberPermission::POST_CHAT),
+        ];
+        let err = replay_membership(initial(), &ops, &ProtocolLimits::default()).unwrap_err();
+        assert_eq!(err.to_string(), "invalid operation #1");
+
+        let ops = [
+            add(
+                &owner,
+                &alice,
+                MemberPermission::ADD_MEMBER | MemberPermission::POST_CHAT,
+            ),
+            add(&alice, &bob, MemberPermission::POST_CHAT),
+        ];
+        let list = replay_membership(initial(), &ops, &ProtocolLimits::default()).unwrap();
+        assert_eq!(list.0.len(), 3);
+    }
+
+    #[test]
+    fn room_metadata_fold() {
+        let (owner, alice) = (
+            SigningKey::from_bytes(&[1; 32]),
+            SigningKey::from_bytes(&[2; 32]),
+        );
+        let room = Uuid::nil();
+        let create = CreateRoomPayload {
+            attrs: RoomAttrs::PUBLIC_READABLE,
+            members: RoomMemberList(vec![RoomMember {
+                permission: MemberPermission::OWNER,
+                user: UserKey::from_signing_key(&owner),
+            }]),
+            title: "initial".into(),
+        };
+        let sign = |key: &SigningKey, payload| WithSig::sign(key, &mut OsRng, payload).unwrap();
+        let set_title = |key: &SigningKey, title: &str| {
+            sign(
+                key,
+                RoomAdminPayload::SetTitle {
+                    room,
+                    title: title.into(),
+                },
+            )
+        };
+        let add_alice = |permission| {
+            sign(
+                &owner,
+                RoomAdminPayload::AddMember {
+                    permission,
+                    room,
+                    user: UserKey::from_signing_key(&alice),
+                },
+            )
+        };
+        let set_attrs =
+            |key: &SigningKey, attrs| sign(key, RoomAdminPayload::UpdateRoomAttrs { attrs, room });
+
+        let (title, attrs) = fold_room_metadata(&create, &[], &ProtocolLimits::default()).unwrap();
+        assert_eq!(
+            (title.as_str(), attrs),
+            ("initial", RoomAttrs::PUBLIC_READABLE)
+        );
+
+        let ops = [
+            set_title(&owner, "first"),
+            set_attrs(&owner, RoomAttrs::empty()),
+            add_alice(MemberPermission::POST_CHAT | MemberPermission::UPDATE_ROOM),
+            set_title(&alice, "second"),
+            set_attrs(&alice, RoomAttrs::PUBLIC_READABLE),
+        ];
+        let (title, attrs) = fold_room_metadata(&create, &ops, &ProtocolLimits::default()).unwrap();
+        assert_eq!(
+            (title.as_str(), attrs),
+            ("second", RoomAttrs::PUBLIC_READABLE)
+        );
+        let (_, attrs) =
+            fold_room_metadata(&create, &ops[..2], &ProtocolLimits::default()).unwrap();
+        assert_eq!(attrs, RoomAttrs::empty());
+
+        // Unauthorized, or with unknown attributes.
+        let ops = [
+            add_alice(MemberPermission::POST_CHAT),
+            set_attrs(&alice, RoomAttrs::empty()),
+        ];
+        let err = fold_room_metadata(&create, &ops, &ProtocolLimits::default()).unwrap_err();
+        assert_eq!(err.to_string(), "invalid operation #1");
+        let ops = [set_attrs(&owner, RoomAttrs::from_bits_retain(1 << 63))];
+        let err = fold_room_metadata(&create, &ops, &ProtocolLimits::default()).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("unknown room attributes"),
+            "{err:#}"
+        );
+
+        let ops = [
+            set_title(&owner, "first"),
+            add_alice(MemberPermission::POST_CHAT),
+            set_title(&alice, "second"),
+        ];
+        let err = fold_room_metadata(&create, &ops, &ProtocolLimits::default()).unwrap_err();
+        assert_eq!(err.to_string(), "invalid operation #2");
+    }
+
+    #[test]
+    fn membership_filter_no_false_negative() {
+        let key = |i: u8| UserKey(SigningKey::from_bytes(&[i; 32]).verifying_key().to_bytes());
+        let mut list = RoomMemberList(Vec::new());
+        for i in 0..100 {
+            list.insert(RoomMember {
+                permission: MemberPermission::POST_CHAT,
+                user: key(i),
+            })
+            .unwrap();
+        }
+        let filter = MembershipFilter::new(&list);
+        assert!(list.0.iter().all(|m| filter.maybe_member(&m.user)));
+
+        let empty = MembershipFilter::new(&RoomMemberList(Vec::new()));
+        assert!(!empty.maybe_member(&key(0)));
+    }
+
+    #[test]
+    fn snapshot_set_title() {
+        let (owner, member) = (UserKey([1; 32]), UserKey([2; 32]));
+        let mut snapshot = RoomSnapshot {
+            attrs: RoomAttrs::empty(),
+            description: None,
+            members: RoomMemberList(vec![
+                RoomMember {
+                    permission: MemberPermission::ALL,
+                    user: owner.clone(),
+                },
+                RoomMember {
+                    permission: MemberPermission::POST_CHAT,
+                    user: member.clone(),
+                },
+            ]),
+            members_version: MembershipVersion::default(),
+            history_watermark: 0,
+            title: "old".into(),
+        };
+        let op = |title: &str| RoomAdminPayload::SetTitle {
+            room: Uuid::nil(),
+            title: title.into(),
+        };
+        snapshot
+            .apply_admin(&member, &op("new"), &ProtocolLimits::default())
+            .unwrap_err();
+        snapshot
+            .apply_admin(&owner, &op(""), &ProtocolLimits::default())
+            .unwrap_err();
+        assert_eq!(snapshot.title, "old");
+        snapshot
+            .apply_admin(&owner, &op("new"), &ProtocolLimits::default())
+            .unwrap();
+        assert_eq!(snapshot.title, "new");
+
+        let op = |description: &str| RoomAdminPayload::SetDescription {
+            description: description.into(),
+            room: Uuid::nil(),
+        };
+        snapshot
+            .apply_admin(&member, &op("about"), &ProtocolLimits::default())
+            .unwrap_err();
+        assert_eq!(snapshot.description, None);
+        snapshot
+            .apply_admin(&owner, &op("about"), &ProtocolLimits::default())
+            .unwrap();
+        assert_eq!(snapshot.description.as_deref(), Some("about"));
+        snapshot
+            .apply_admin(&owner, &op(""), &ProtocolLimits::default())
+            .unwrap();
+        assert_eq!(snapshot.description, None);
+    }
+
+    #[test]
+    fn admin_op_authorization() {
+        let (room, user) = (Uuid::nil(), UserKey([1; 32]));
+        let cases = [
+            (
+                RoomAdminPayload::AddMember {
+                    permission: MemberPermission::POST_CHAT,
+                    room,
+                    user: user.clone(),
+                },
+                MemberPermission::ADD_MEMBER | MemberPermission::POST_CHAT,
+            ),
+            (
+                RoomAdminPayload::RemoveMember {
+                    room,
+                    user: user.clone(),
+                },
+                MemberPermission::REMOVE_MEMBER,
+            ),
+            (
+                RoomAdminPayload::SetTitle {
+                    room,
+                    title: "title".into(),
+                },
+                MemberPermission::UPDATE_ROOM,
+            ),
+            (
+                RoomAdminPayload::ClearHistory { room, up_to: 42 },
+                MemberPermission::UPDATE_ROOM,
+            ),
+            (
+                RoomAdminPayload::SetDescription {
+                    description: "about".into(),
+                    room,
+                },
+                MemberPermission::UPDATE_ROOM,
+            ),
+        ];
+        for (op, required) in cases {
+            assert!(is_admin_op_authorized(&op, required), "{op:?}");
+            assert!(is_admin_op_authorized(&op, MemberPermission::ALL), "{op:?}");
+            assert!(!is_admin_op_authorized(&op, !required), "{op:?}");
+        }
+    }
+
+    #[test]
+    fn sequence_gaps() {
+        assert_eq!(sequence_gap(None, 0), None);
+        assert_eq!(sequence_gap(None, 2), Some(0..2));
+        assert_eq!(sequence_gap(Some(4), 5), None);
+        assert_eq!(sequence_gap(Some(4), 8), Some(5..8));
+        assert_eq!(sequence_gap(Some(4), 4), None);
+        assert_eq!(sequence_gap(Some(4), 1), None);
+    }
+
+    #[test]
+    fn sequence_tracker() {
+        let (alice, bob) = (UserKey([1; 32]), UserKey([2; 32]));
+        let mut tracker = SequenceTracker::new();
+        // In order.
+        assert_eq!(tracker.observe(&alice, 0), []);
+        assert_eq!(tracker.observe(&alice, 1), []);
+        // Gap.
+        assert_eq!(tracker.observe(&alice, 5), [Range { start: 2, end: 5 }]);
+        // Duplicated, or filling the gap later.
+        assert_eq!(tracker.observe(&alice, 5), []);
+        assert_eq!(tracker.observe(&alice, 3), []);
+        assert_eq!(tracker.observe(&alice, 6), []);
+        // Independent between users.
+        assert_eq!(tracker.observe(&bob, 2), [Range { start: 0, end: 2 }]);
+    }
+
+    #[test]
+    fn membership_version() {
+        let owner = UserKey([1; 32]);
+        let mut snapshot = RoomSnapshot::from(CreateRoomPayload {
+            attrs: RoomAttrs::empty(),
+            members: RoomMemberList(vec![RoomMember {
+                permission: MemberPermission::ALL,
+                user: owner.clone(),
+            }]),
+            title: "title".into(),
+        });
+        let (room, user) = (Uuid::nil(), UserKey([2; 32]));
+        let add = RoomAdminPayload::AddMember {
+            permission: MemberPermission::POST_CHAT,
+            room,
+            user: user.clone(),
+        };
+        snapshot
+            .apply_admin(&owner, &add, &ProtocolLimits::default())
+            .unwrap();
+        assert_eq!(snapshot.members_version, MembershipVersion(1));
+
+        // Rejected.
+        snapshot
+            .apply_admin(&owner, &add, &ProtocolLimits::default())
+            .unwrap_err();
+        snapshot
+            .apply_admin(&user, &add, &ProtocolLimits::default())
+            .unwrap_err();
+        // Not about membership.
+        let set_title = RoomAdminPayload::SetTitle {
+            room,
+            title: "new".into(),
+        };
+        snapshot
+            .apply_admin(&owner, &set_title, &ProtocolLimits::default())
+            .unwrap();
+        assert_eq!(snapshot.members_version, MembershipVersion(1));
+
+        let remove = RoomAdminPayload::RemoveMember { room, user };
+        snapshot
+            .apply_admin(&owner, &remove, &ProtocolLimits::default())
+            .unwrap();
+        assert_eq!(snapshot.members_version, MembershipVersion(2));
+    }
+
+    #[test]
+    fn leave_room() {
+        let (owner, user) = (UserKey([1; 32]), UserKey([2; 32]));
+        let mut snapshot = RoomSnapshot::from(CreateRoomPayload {
+            attrs: RoomAttrs::empty(),
+            members: RoomMemberList(vec![
+                RoomMember {
+                    permission: MemberPermission::ALL,
+                    user: owner.clone(),
+                },
+                RoomMember {
+                    permission: MemberPermission::POST_CHAT,
+                    user: user.clone(),
+                },
+            ]),
+            title: "title".into(),
+        });
+        snapshot.apply_leave(&user).unwrap();
+        assert!(!snapshot.members.contains(&user));
+        assert_eq!(snapshot.members_version, MembershipVersion(1));
+        snapshot.apply_leave(&user).unwrap_err();
+        assert_eq!(snapshot.members_version, MembershipVersion(1));
+
+        // The last member cannot leave or be removed, which would leave an invalid empty list.
+        snapshot.apply_leave(&owner).unwrap_err();
+        let remove = RoomAdminPayload::RemoveMember {
+            room: Uuid::nil(),
+            user: owner.clone(),
+        };
+        snapshot
+            .apply_admin(&owner, &remove, &ProtocolLimits::default())
+            .unwrap_err();
+        assert_eq!(snapshot.members.0.len(), 1);
+        assert_eq!(snapshot.members_version, MembershipVersion(1));
+    }
+
+    #[test]
+    fn quorum() {
+        let member = |b: u8, permission| RoomMember {
+            permission,
+            user: UserKey([b; 32]),
+        };
+        let list = RoomMemberList(vec![
+            member(1, MemberPermission::ALL),
+            member(2, MemberPermission::ADD_MEMBER),
+            member(
+                3,
+                MemberPermission::REMOVE_MEMBER | MemberPermission::POST_CHAT,
+            ),
+            member(
+                4,
+                MemberPermission::POST_CHAT | MemberPermission::UPDATE_ROOM,
+            ),
+        ]);
+        assert_eq!(admin_quorum(&list), 3);
+        assert_eq!(
+            admin_quorum(&RoomMemberList(vec![member(
+                4,
+                MemberPermission::POST_CHAT
+            )])),
+            0
+        );
+        assert_eq!(admin_quorum(&RoomMemberList(vec![])), 0);
+
+        let key = |b: u8| UserKey([b; 32]);
+        check_quorum(&list, &[key(1), key(3)], 2).unwrap();
+        check_quorum(&list, &[key(1), key(1)], 2).unwrap_err();
+        check_quorum(&list, &[key(1), key(4)], 2).unwrap_err();
+        check_quorum(&list, &[key(1), key(2), key(3)], 4).unwrap_err();
+    }
+
+    #[test]
+    fn create_room_gate() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let create = |permission| CreateRoomPayload {
+            attrs: RoomAttrs::empty(),
+            members: RoomMemberList(vec![RoomMember {
+                permission,
+                user: UserKey(key.verifying_key().to_bytes()),
+            }]),
+            title: "test".into(),
+        };
+        let opts = VerifyOptions::default();
+        let signed = WithSig::sign(&key, &mut OsRng, create(MemberPermission::ALL)).unwrap();
+        verify_create_room(&signed, &opts).unwrap();
+
+        let signed = WithSig::sign(&key, &mut OsRng, create(MemberPermission::POST_CHAT)).unwrap();
+        let err = verify_create_room(&signed, &opts).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MemberListError>(),
+            Some(&MemberListError::CreatorMissing)
+        );
+
+        let other = SigningKey::from_bytes(&[2; 32]);
+        let signed = WithSig::sign(&other, &mut OsRng, create(MemberPermission::ALL)).unwrap();
+        verify_create_room(&signed, &opts).unwrap_err();
+    }
+
+    #[test]
+    fn import_rooms_partial() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let create = |permission| {
+            let payload = CreateRoomPayload {
+                attrs: RoomAttrs::empty(),
+                members: RoomMemberList(vec![RoomMember {
+                    permission,
+                    user: UserKey::from_signing_key(&key),
+                }]),
+                title: "test".into(),
+            };
+            WithSig::sign(&key, &mut OsRng, payload).unwrap()
+        };
+        let mut tampered = create(MemberPermission::ALL);
+        tampered.signee.payload.title = "tampered".into();
+        let payloads = vec![
+            create(MemberPermission::ALL),
+            tampered,
+            create(MemberPermission::POST_CHAT),
+            create(MemberPermission::ALL),
+        ];
+
+        let ret = import_rooms(payloads, &VerifyOptions::default());
+        assert_eq!(ret.len(), 4);
+        assert!(matches!(ret[1], Err(VerifyError::InvalidSignature)));
+        assert!(matches!(ret[2], Err(VerifyError::InvalidPayload(_))));
+        let (a, b) = (ret[0].as_ref().unwrap(), ret[3].as_ref().unwrap());
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn admin_batch() {
+        let (owner, user) = (UserKey([1; 32]), UserKey([2; 32]));
+        let mut snapshot = RoomSnapshot::from(CreateRoomPayload {
+            attrs: RoomAttrs::empty(),
+            members: RoomMemberList(vec![RoomMember {
+                permission: MemberPermission::ALL,
+                user: owner.clone(),
+            }]),
+            title: "title".into(),
+        });
+        let room = Uuid::nil();
+        let add = || RoomAdminPayload::AddMember {
+            permission: MemberPermission::ADD_MEMBER,
+            room,
+            user: user.clone(),
+        };
+        let set_title = || RoomAdminPayload::SetTitle {
+            room,
+            title: "new".into(),
+        };
+        let batch = |ops| RoomAdminPayload::Batch {
+            ops: AdminBatch(ops),
+            room,
+        };
+
+        // The second addition fails, thus nothing is applied.
+        snapshot
+            .apply_admin(
+                &owner,
+                &batch(vec![add(), set_title(), add()]),
+                &ProtocolLimits::default(),
+            )
+            .unwrap_err();
+        assert!(!snapshot.members.contains(&user));
+        assert_eq!(snapshot.title, "title");
+        assert_eq!(snapshot.members_version, MembershipVersion(0));
+
+        snapshot
+            .apply_admin(
+                &owner,
+                &batch(vec![add(), set_title()]),
+                &ProtocolLimits::default(),
+            )
+            .unwrap();
+        assert!(snapshot.members.contains(&user));
+        assert_eq!(snapshot.title, "new");
+        assert_eq!(snapshot.members_version, MembershipVersion(1));
+
+        // `user` can add members but not change the title.
+        let retitle = batch(vec![add(), set_title()]);
+        assert!(!is_admin_op_authorized(
+            &retitle,
+            MemberPermission::ADD_MEMBER
+        ));
+        snapshot
+            .apply_admin(&user, &retitle, &ProtocolLimits::default())
+            .unwrap_err();
+    }
+
+    #[test]
+    fn post_chat_gate() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let author = UserKey(key.verifying_key().to_bytes());
+        let chat = ChatPayload {
+            room: Uuid::nil(),
+            sequence: None,
+            text: "hello".into(),
+        };
+        let item = ChatItem::sign(&key, &mut OsRng, chat).unwrap();
+        let members = |permission| {
+            RoomMemberList(vec![RoomMember {
+                permission,
+                user: author.clone(),
+            }])
+        };
+        let opts = VerifyOptions::default();
+
+        verify_chat(&item, &members(MemberPermission::POST_CHAT), &opts).unwrap();
+        let err = verify_chat(&item, &members(MemberPermission::ADD_MEMBER), &opts).unwrap_err();
+        assert!(err.to_string().contains("no permission"), "{err}");
+        let others = RoomMemberList(vec![RoomMember {
+            permission: MemberPermission::ALL,
+            user: UserKey([2; 32]),
+        }]);
+        let err = verify_chat(&item, &others, &opts).unwrap_err();
+        assert!(err.to_string().contains("not a member"), "{err}");
+    }
+
+    #[test]
+    fn pin_authorization() {
+        let member = |b: u8, permission| RoomMember {
+            permission,
+            user: UserKey([b; 32]),
+        };
+        let list = RoomMemberList(vec![
+            member(1, MemberPermission::UPDATE_ROOM),
+            member(2, MemberPermission::POST_CHAT),
+        ]);
+        check_pin_authorized(&list, &UserKey([1; 32])).unwrap();
+        check_pin_authorized(&list, &UserKey([2; 32])).unwrap_err();
+        check_pin_authorized(&list, &UserKey([3; 32])).unwrap_err();
+    }
+
+    #[test]
+    fn invite_token() {
+        let admin = SigningKey::from_bytes(&[1; 32]);
+        let joiner = UserKey([2; 32]);
+        let token = WithSig::sign(
+            &admin,
+            &mut OsRng,
+            InviteToken {
+                expires_at: 1000,
+                permission: MemberPermission::POST_CHAT,
+                room: Uuid::nil(),
+            },
+        )
+        .unwrap();
+
+        let member = redeem(&token, &joiner, 999).unwrap();
+        assert_eq!(member.user, joiner);
+        assert_eq!(member.permission, MemberPermission::POST_CHAT);
+
+        let err = redeem(&token, &joiner, 1000).unwrap_err();
+        assert_eq!(err.to_string(), "invite token expired");
+
+        let mut forged = token;
+        forged.signee.payload.permission = MemberPermission::ALL;
+        redeem(&forged, &joiner, 999).unwrap_err();
+    }
+
+    #[test]
+    fn preflight() {
+        let room = Uuid::nil();
+        let ops = [
+            RoomAdminPayload::AddMember {
+                permission: MemberPermission::POST_CHAT,
+                room,
+                user: UserKey([2; 32]),
+            },
+            RoomAdminPayload::RemoveMember {
+                room,
+                user: UserKey([3; 32]),
+            },
+            RoomAdminPayload::SetTitle {
+                room,
+                title: "new".into(),
+            },
+        ];
+        let perm = MemberPermission::POST_CHAT
+            | MemberPermission::ADD_MEMBER
+            | MemberPermission::UPDATE_ROOM;
+        let ret = preflight_batch(&ops, perm);
+        assert_eq!(
+            ret,
+            [
+                Ok(()),
+                Err("not authorized with permission POST_CHAT|ADD_MEMBER|UPDATE_ROOM".into()),
+                Ok(()),
+            ],
+        );
+        assert!(preflight_batch(&ops, MemberPermission::OWNER)
+            .iter()
+            .all(Result::is_ok));
+    }
+
+    #[test]
+    fn dm_room() {
+        let (a, b, c) = (UserKey([1; 32]), UserKey([2; 32]), UserKey([3; 32]));
+        let id = dm_room_id(&a, &b);
+        assert_eq!(id, dm_room_id(&b, &a));
+        assert_eq!(id.get_version_num(), 5);
+        assert_ne!(id, dm_room_id(&a, &c));
+        assert_ne!(id, dm_room_id(&b, &c));
+        assert_ne!(dm_room_id(&a, &a), dm_room_id(&b, &b));
+        // Pinned, since changing it breaks existing rooms.
+        assert_eq!(id.to_string(), "3ab24500-709c-512d-bb5f-2e8a5050bf45");
+    }
+
+    #[test]
+    fn add_member_escalation() {
+        let (admin, user) = (UserKey([1; 32]), UserKey([2; 32]));
+        let held = MemberPermission::ADD_MEMBER | MemberPermission::POST_CHAT;
+        let mut snapshot = RoomSnapshot::from(CreateRoomPayload {
+            attrs: RoomAttrs::empty(),
+            members: RoomMemberList(vec![RoomMember {
+                permission: held,
+                user: admin.clone(),
+            }]),
+            title: "title".into(),
+        });
+        let add = |permission| RoomAdminPayload::AddMember {
+            permission,
+            room: Uuid::nil(),
+            user: user.clone(),
+        };
+
+        for granted in [MemberPermission::ALL, MemberPermission::REMOVE_MEMBER] {
+            let op = add(granted);
+            assert!(!is_admin_op_authorized(&op, held));
+            let err = snapshot
+                .apply_admin(&admin, &op, &ProtocolLimits::default())
+                .unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<EscalationError>(),
+                Some(&EscalationError { granted, held }),
+            );
+            // Also inside a batch.
+            let batch = RoomAdminPayload::Batch {
+                ops: AdminBatch(vec![op]),
+                room: Uuid::nil(),
+            };
+            let err = snapshot
+                .apply_admin(&admin, &batch, &ProtocolLimits::default())
+                .unwrap_err();
+            assert!(err.is::<EscalationError>(), "{err:?}");
+        }
+        assert!(!snapshot.members.contains(&user));
+
+        snapshot
+            .apply_admin(&admin, &add(held), &ProtocolLimits::default())
+            .unwrap();
+        assert_eq!(snapshot.members.get(&user).unwrap().permission, held);
+    }
+
+    #[test]
+    fn room_log() {
+        let (owner, alice, bob) = (
+            SigningKey::from_bytes(&[1; 32]),
+            SigningKey::from_bytes(&[2; 32]),
+            SigningKey::from_bytes(&[3; 32]),
+        );
+        let room = Uuid::new_v4();
+        let sign = |key: &SigningKey, payload: serde_json::Value| {
+            let payload = serde_json::from_value::<AnyPayload>(payload).unwrap();
+            WithSig::sign(key, &mut OsRng, payload).unwrap()
+        };
+        fn to_value(v: &impl Serialize) -> serde_json::Value {
+            serde_json::to_value(v).unwrap()
+        }
+        // `WithSig` is not `Clone`.
+        fn dup(log: &[WithSig<AnyPayload>]) -> Vec<WithSig<AnyPayload>> {
+            serde_json::from_value(to_value(&log)).unwrap()
+        }
+
+        let create = sign(
+            &owner,
+            to_value(&CreateRoomPayload {
+                attrs: RoomAttrs::empty(),
+                members: RoomMemberList(vec![RoomMember {
+                    permission: MemberPermission::OWNER,
+                    user: UserKey::from_signing_key(&owner),
+                }]),
+                title: "title".into(),
+            }),
+        );
+        let add_alice = sign(
+            &owner,
+            to_value(&RoomAdminPayload::AddMember {
+                permission: MemberPermission::POST_CHAT,
+                room,
+                user: UserKey::from_signing_key(&alice),
+            }),
+        );
+        let chat = |key: &SigningKey, text: &str| {
+            sign(
+                key,
+                to_value(&ChatPayload {
+                    room,
+                    sequence: None,
+                    text: text.into(),
+                }),
+            )
+        };
+        let hello = chat(&alice, "hello");
+        let target = MessageId::of(&hello);
+        let edit = |key: &SigningKey, target| {
+            sign(
+                key,
+                to_value(&EditChatPayload {
+                    room,
+                    target,
+                    text: "edited".into(),
+                }),
+            )
+        };
+        let delete =
+            |key: &SigningKey, target| sign(key, to_value(&DeleteChatPayload { room, target }));
+        let pin = sign(
+            &owner,
+            to_value(&PinPayload {
+                pin: true,
+                room,
+                target,
+            }),
+        );
+        let leave = |key: &SigningKey| sign(key, to_value(&LeavePayload { room }));
+
+        let log = vec![
+            create,
+            add_alice,
+            hello,
+            edit(&alice, target),
+            pin,
+            delete(&owner, target),
+            leave(&alice),
+        ];
+        let snapshot = verify_room_log(room, &log, &ProtocolLimits::default()).unwrap();
+        assert_eq!(snapshot.members.0.len(), 1);
+        assert_eq!(snapshot.members_version, MembershipVersion(2));
+
+        let check_err = |log: &[WithSig<AnyPayload>], idx: usize, cause: &str| {
+            let err = verify_room_log(room, log, &ProtocolLimits::default()).unwrap_err();
+            assert_eq!(err.to_string(), format!("invalid log entry #{idx}"));
+            let root = format!("{:#}", err);
+            assert!(root.contains(cause), "{root}");
+        };
+
+        // Tampered signature.
+        let mut tampered = dup(&log);
+        if let AnyPayload::Chat(p) = &mut tampered[2].signee.payload {
+            p.text = "tampered".into();
+        }
+        check_err(&tampered, 2, "signature");
+        // Not starting with the creation.
+        check_err(&log[1..], 0, "not a room creation");
+        // Chat from a non-member.
+        let mut tampered = dup(&log[..2]);
+        tampered.push(chat(&bob, "spam"));
+        check_err(&tampered, 2, "is not a member");
+        // Edit of someone else's chat.
+        let mut tampered = dup(&log[..3]);
+        tampered.push(edit(&owner, target));
+        check_err(&tampered, 3, "edit by");
+        // Deletion by a non-author without permission.
+        let mut tampered = dup(&log[..3]);
+        tampered.push(chat(&owner, "mine"));
+        let owner_chat = MessageId::of(&tampered[3]);
+        tampered.push(delete(&alice, owner_chat));
+        check_err(&tampered, 4, "no permission to delete");
+        // Edit after deletion, and of a nonexistent chat.
+        let mut tampered = dup(&log[..6]);
+        tampered.push(edit(&alice, target));
+        check_err(&tampered, 6, "does not exist or is deleted");
+        let mut tampered = dup(&log[..3]);
+        tampered.push(edit(&alice, MessageId([0; 32])));
+        check_err(&tampered, 3, "does not exist or is deleted");
+        // Items of another room.
+        let err = verify_room_log(Uuid::nil(), &log, &ProtocolLimits::default()).unwrap_err();
+        assert_eq!(err.to_string(), "invalid log entry #1");
+        assert!(format!("{err:#}").contains("another room"), "{err:#}");
+        // Reordered: chat before being added.
+        let mut tampered = dup(&log[..3]);
+        tampered.swap(1, 2);
+        check_err(&tampered, 1, "is not a member");
+        // Edit after clearing the history.
+        let mut tampered = dup(&log[..3]);
+        tampered.push(sign(
+            &owner,
+            to_value(&RoomAdminPayload::ClearHistory {
+                room,
+                up_to: u64::MAX,
+            }),
+        ));
+        tampered.push(edit(&alice, target));
+        check_err(&tampered, 4, "does not exist or is deleted");
+        // Chats signed before the watermark but appended after the clear.
+        tampered.truncate(4);
+        let late = chat(&alice, "late");
+        let late_id = MessageId::of(&late);
+        tampered.push(late);
+        verify_room_log(room, &tampered, &ProtocolLimits::default()).unwrap();
+        tampered.push(edit(&alice, late_id));
+        check_err(&tampered, 5, "does not exist or is deleted");
+    }
+
+    #[test]
+    fn clear_history() {
+        let (owner, member) = (UserKey([1; 32]), UserKey([2; 32]));
+        let mut snapshot = RoomSnapshot::from(CreateRoomPayload {
+            attrs: RoomAttrs::empty(),
+            members: RoomMemberList(vec![
+                RoomMember {
+                    permission: MemberPermission::ALL,
+                    user: owner.clone(),
+                },
+                RoomMember {
+                    permission: MemberPermission::POST_CHAT,
+                    user: member.clone(),
+                },
+            ]),
+            title: "title".into(),
+        });
+        let op = |up_to| RoomAdminPayload::ClearHistory {
+            room: Uuid::nil(),
+            up_to,
+        };
+        snapshot
+            .apply_admin(&member, &op(42), &ProtocolLimits::default())
+            .unwrap_err();
+        assert_eq!(snapshot.history_watermark, 0);
+        snapshot
+            .apply_admin(&owner, &op(42), &ProtocolLimits::default())
+            .unwrap();
+        assert_eq!(snapshot.history_watermark, 42);
+        // The watermark never goes back.
+        snapshot
+            .apply_admin(&owner, &op(1), &ProtocolLimits::default())
+            .unwrap();
+        assert_eq!(snapshot.history_watermark, 42);
+        assert_eq!(snapshot.members_version, MembershipVersion(0));
+
+        let json = serde_json::to_value(op(42)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "typ": "clear_history",
+                "room": Uuid::nil(),
+                "up_to": 42,
+            })
+        );
+        let payload = serde_json::from_value::<AnyPayload>(json).unwrap();
+        assert!(matches!(
+            payload,
+            AnyPayload::RoomAdmin(RoomAdminPayload::ClearHistory { up_to: 42, .. })
+        ));
+    }
+
+    #[test]
+    fn receipt_marks() {
+        let (alice, bob) = (UserKey([1; 32]), UserKey([2; 32]));
+        let mut marks = ReceiptMarks::new();
+        assert_eq!(marks.get(&alice), None);
+        assert!(marks.advance(&alice, 5));
+        assert!(!marks.advance(&alice, 5));
+        assert!(!marks.advance(&alice, 3));
+        assert!(marks.advance(&alice, 7));
+        assert!(marks.advance(&bob, 1));
+        assert_eq!((marks.get(&alice), marks.get(&bob)), (Some(7), Some(1)));
+    }
+}
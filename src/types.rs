@@ -1,42 +1,368 @@
 //! NB. All structs here that are part of signee must be lexically sorted, as RFC8785.
 //! This is tested by `canonical_fields_sorted`.
 //! See: https://www.rfc-editor.org/rfc/rfc8785
-//! FIXME: `typ` is still always the first field because of `serde`'s implementation.
-use std::fmt;
-use std::time::SystemTime;
+//! FIXME: `typ` is still always the first field because of `serde`'s implementation. New payload
+//! types can opt into the fully sorted form of [`SORTED_VERSION`] via [`Canonical`], while
+//! existing ones keep `typ` first for compatibility of signatures.
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use std::{fmt, io};
 
 use anyhow::{ensure, Context};
-use bitflags::bitflags;
+use bitflags::{bitflags, Flags};
 use bitflags_serde_shim::impl_serde_for_bitflags;
+#[cfg(feature = "sealed-sender")]
+use chacha20poly1305::aead::{Aead, KeyInit};
+#[cfg(feature = "sealed-sender")]
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
 use ed25519_dalek::{
     Signature, Signer, SigningKey, VerifyingKey, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH,
 };
 use rand_core::RngCore;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use unicode_segmentation::UnicodeSegmentation;
 use uuid::Uuid;
 
 const TIMESTAMP_TOLERENCE: u64 = 90;
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Tunable limits of the protocol, to be checked on untrusted inputs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolLimits {
+    /// The maximum number of operations in a single batched admin operation. It is only checked
+    /// on validation, not on parsing [`AdminBatch`], whose size is bounded by `max_frame_len`.
+    pub max_batch_ops: usize,
+    /// The maximum length in bytes of a serialized signed item.
+    pub max_frame_len: usize,
+    /// The maximum nesting depth of objects and arrays in a serialized signed item. See
+    /// [`check_json_depth`].
+    pub max_json_depth: usize,
+    /// The maximum number of members in a room.
+    pub max_members: usize,
+    /// The maximum length in bytes of a chat text.
+    pub max_text_len: usize,
+    /// The maximum number of items a user may post at once. See [`crate::ratelimit`].
+    pub rate_burst: u32,
+    /// The interval in seconds to regain a post after reaching [`ProtocolLimits::rate_burst`].
+    pub rate_refill_secs: u64,
+    /// The maximum difference in seconds between a signed timestamp and the current time.
+    pub timestamp_tolerance: u64,
+}
+
+impl Default for ProtocolLimits {
+    fn default() -> Self {
+        Self {
+            max_batch_ops: 64,
+            max_frame_len: 4 << 10, // 4KiB
+            max_json_depth: 32,
+            max_members: 1024,
+            max_text_len: 2 << 10, // 2KiB
+            rate_burst: 10,
+            rate_refill_secs: 3,
+            timestamp_tolerance: TIMESTAMP_TOLERENCE,
+        }
+    }
+}
+
+/// Options for [`WithSig::verify_with_options`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyOptions {
+    pub limits: ProtocolLimits,
+    /// Bypass the timestamp check entirely, overriding `timestamp`, for batch historical sync
+    /// from a trusted peer server over an already authenticated channel only.
+    ///
+    /// NB. Like [`TimestampPolicy::Informational`], this disables replay protection by
+    /// timestamps, so any item ever signed is accepted again. Never set it for client inputs.
+    pub skip_timestamp: bool,
+    pub timestamp: TimestampPolicy,
+}
+
+/// The serialization format of items over the wire, negotiated with [`choose_format`].
+///
+/// Signatures are always over the canonical JSON form regardless of the wire format.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WireFormat {
+    /// The default, which every client must support.
+    #[default]
+    Json,
+    /// The compact binary format, for bandwidth-constrained clients.
+    ///
+    /// NB. It is not implemented by this crate, so servers using it must bring their own.
+    Binary,
+}
+
+/// Choose the wire format supported by both the client and the server, preferring
+/// [`WireFormat::Binary`], and falling back to [`WireFormat::Json`].
+pub fn choose_format(client_supports: &[WireFormat], server_supports: &[WireFormat]) -> WireFormat {
+    let both = |fmt| client_supports.contains(&fmt) && server_supports.contains(&fmt);
+    if both(WireFormat::Binary) {
+        WireFormat::Binary
+    } else {
+        WireFormat::Json
+    }
+}
+
+/// How the signed timestamp is checked on verification.
+///
+/// Relaxing the freshness check lets offline-first clients sign while disconnected and deliver
+/// later, at the cost of replay protection: a [`ReplayWindow`](crate::replay::ReplayWindow) only
+/// catches replays within the window it covers, thus it must cover as long as `max_age` for
+/// [`TimestampPolicy::Offline`], and cannot catch replays at all for
+/// [`TimestampPolicy::Informational`]. An accepted timestamp only tells when the author claims
+/// to have signed it, so the receiver should record its own receive time for ordering.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimestampPolicy {
+    /// The timestamp must be within [`ProtocolLimits::timestamp_tolerance`] of the current time.
+    #[default]
+    Fresh,
+    /// The timestamp may be up to `max_age` seconds in the past, but still must not be in the
+    /// future beyond [`ProtocolLimits::timestamp_tolerance`].
+    Offline { max_age: u64 },
+    /// The timestamp is not checked at all. Only the signature is.
+    Informational,
+}
+
+/// The reason of a failed verification of a signed item.
+#[derive(Debug)]
+pub enum VerifyError {
+    /// The timestamp is outside of the window accepted by the [`TimestampPolicy`], either too old
+    /// or too far in the future, by `expired_by_secs` seconds beyond the window boundary.
+    ExpiredTimestamp { expired_by_secs: u64 },
+    /// The author key is not a valid Ed25519 public key.
+    InvalidKey,
+    /// The signature does not match.
+    InvalidSignature,
+    /// The payload is rejected by [`Validate`].
+    InvalidPayload(anyhow::Error),
+    /// The signee cannot be serialized into the canonical form.
+    Serialize(serde_json::Error),
+    /// The [`Signee::version`] is not [`PROTOCOL_VERSION`] or [`SORTED_VERSION`].
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ExpiredTimestamp { expired_by_secs } => {
+                write!(f, "invalid timestamp, expired by {expired_by_secs}s")
+            }
+            Self::InvalidKey => f.write_str("invalid public key"),
+            Self::InvalidSignature => f.write_str("invalid signature"),
+            Self::InvalidPayload(err) => write!(f, "invalid payload: {err}"),
+            Self::Serialize(_) => f.write_str("failed to serialize"),
+            Self::UnsupportedVersion(v) => write!(f, "unsupported protocol version {v}"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Serialize(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Payload-specific checks of the content, beyond what the type itself guarantees.
+///
+/// The default implementation accepts everything.
+pub trait Validate {
+    fn validate(&self, _limits: &ProtocolLimits) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Checks against the [`MessageId`] of the signed item containing this payload.
+    fn validate_with_id(&self, _id: &MessageId) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+impl Validate for serde_json::Value {}
+
+/// Collect all user keys referenced by a payload, eg. mentions or targets of admin operations,
+/// in order of first appearance and without duplicates.
+///
+/// The default implementation references no keys. For a [`WithSig`], it includes the author.
+pub trait ReferencedKeys {
+    fn referenced_keys(&self) -> Vec<UserKey> {
+        Vec::new()
+    }
+}
+
+impl<T: ReferencedKeys> ReferencedKeys for WithSig<T> {
+    fn referenced_keys(&self) -> Vec<UserKey> {
+        let mut keys = vec![self.signee.user.clone()];
+        keys.extend(self.signee.payload.referenced_keys());
+        dedup_keys(keys)
+    }
+}
+
+/// Remove duplicated keys, keeping the first occurrence of each.
+fn dedup_keys(keys: Vec<UserKey>) -> Vec<UserKey> {
+    let mut seen = HashSet::with_capacity(keys.len());
+    keys.into_iter()
+        .filter(|k| seen.insert(k.clone()))
+        .collect()
+}
+
+/// An Ed25519 public key identifying a user.
+///
+/// The ordering is the lexical ordering of the raw key bytes.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 #[serde(transparent)]
-pub struct UserKey(#[serde(with = "hex::serde")] pub [u8; PUBLIC_KEY_LENGTH]);
+pub struct UserKey(#[serde(with = "hex_array")] pub [u8; PUBLIC_KEY_LENGTH]);
+
+impl UserKey {
+    /// The user key of a local signing key, ie. its verifying key.
+    pub fn from_signing_key(key: &SigningKey) -> Self {
+        Self(key.verifying_key().to_bytes())
+    }
+}
+
+impl From<&SigningKey> for UserKey {
+    fn from(key: &SigningKey) -> Self {
+        Self::from_signing_key(key)
+    }
+}
+
+impl UserKey {
+    /// Stream the lowercase hex form into `f`, the same as [`Display`](fmt::Display), without an
+    /// intermediate buffer.
+    pub fn write_hex(&self, f: &mut impl fmt::Write) -> fmt::Result {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        for b in self.0 {
+            f.write_char(DIGITS[usize::from(b >> 4)].into())?;
+            f.write_char(DIGITS[usize::from(b & 0xF)].into())?;
+        }
+        Ok(())
+    }
+
+    /// A stable shard index in `0..shards` of this key, for routing a user's data to a
+    /// consistent shard. It is the first two bytes (as big endian) modulo `shards`, which is
+    /// evenly distributed since keys are.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is zero.
+    pub fn shard(&self, shards: u16) -> u16 {
+        assert_ne!(shards, 0, "zero shards");
+        u16::from_be_bytes([self.0[0], self.0[1]]) % shards
+    }
+}
 
 impl fmt::Display for UserKey {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut buf = [0u8; PUBLIC_KEY_LENGTH * 2];
-        hex::encode_to_slice(self.0, &mut buf).unwrap();
-        f.write_str(std::str::from_utf8(&buf).unwrap())
+        self.write_hex(f)
+    }
+}
+
+impl UserKey {
+    /// Convert the Ed25519 public key to its X25519 (Montgomery form) equivalent via the birational
+    /// map `u = (1 + y) / (1 - y)`, for Diffie-Hellman between identity keys.
+    pub fn to_montgomery(&self) -> anyhow::Result<[u8; 32]> {
+        let key = VerifyingKey::from_bytes(&self.0).context("invalid public key")?;
+        Ok(key.to_montgomery().to_bytes())
+    }
+}
+
+impl FromStr for UserKey {
+    type Err = UserKeyParseError;
+
+    /// Parse a hex-encoded public key, which must also be a valid curve point.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((pos, ch)) = s.char_indices().find(|(_, c)| !c.is_ascii_hexdigit()) {
+            return Err(UserKeyParseError::InvalidChar { ch, pos });
+        }
+        if s.len() != PUBLIC_KEY_LENGTH * 2 {
+            return Err(UserKeyParseError::InvalidLength(s.len()));
+        }
+        let mut buf = [0u8; PUBLIC_KEY_LENGTH];
+        hex::decode_to_slice(s, &mut buf).expect("checked");
+        VerifyingKey::from_bytes(&buf).map_err(|_| UserKeyParseError::InvalidPoint)?;
+        Ok(Self(buf))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UserKeyParseError {
+    /// The input has a wrong number of hex digits.
+    InvalidLength(usize),
+    /// The input contains a non-hex character.
+    InvalidChar { ch: char, pos: usize },
+    /// The input is not a valid Ed25519 public key.
+    InvalidPoint,
+}
+
+impl fmt::Display for UserKeyParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLength(len) => write!(
+                f,
+                "invalid key length: expected {} hex digits, got {len}",
+                PUBLIC_KEY_LENGTH * 2,
+            ),
+            Self::InvalidChar { ch, pos } => {
+                write!(f, "invalid character {ch:?} at position {pos}")
+            }
+            Self::InvalidPoint => f.write_str("invalid public key"),
+        }
     }
 }
 
+impl std::error::Error for UserKeyParseError {}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct WithSig<T> {
-    #[serde(with = "hex::serde")]
+    #[serde(with = "hex_array")]
     pub sig: [u8; SIGNATURE_LENGTH],
     pub signee: Signee<T>,
 }
 
+/// A signed item as stored by a server, with the server's own receive time.
+///
+/// `received_at` (in seconds since UNIX epoch) is authoritative for ordering and auditing on the
+/// server, since the signed [`Signee::timestamp`] is controlled by the client. The signed one is
+/// still used for freshness checks on verification.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredItem<T> {
+    pub item: WithSig<T>,
+    pub received_at: u64,
+}
+
+impl<T> StoredItem<T> {
+    /// Wrap an item received just now.
+    pub fn received_now(item: WithSig<T>) -> Self {
+        Self {
+            item,
+            received_at: get_timestamp(),
+        }
+    }
+}
+
+/// Signed items are identified by their signatures, since a signature determines the signee
+/// for a verified item. Signees are not compared, thus items should be verified before put into
+/// sets keyed this way.
+impl<T> PartialEq for WithSig<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.sig == other.sig
+    }
+}
+
+impl<T> Eq for WithSig<T> {}
+
+impl<T> std::hash::Hash for WithSig<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.sig.hash(state);
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Signee<T> {
@@ -44,218 +370,4340 @@ pub struct Signee<T> {
     pub payload: T,
     pub timestamp: u64,
     pub user: UserKey,
+    /// The version of the signed envelope and its canonical form. Only [`PROTOCOL_VERSION`] and
+    /// [`SORTED_VERSION`] are accepted on verification of live inputs, and [`LEGACY_VERSION`] is
+    /// additionally accepted by [`WithSig::verify_signature`] for history.
+    pub version: u32,
+}
+
+/// The canonical form of [`LEGACY_VERSION`] signees, which is the current one without `version`.
+#[derive(Serialize)]
+struct LegacySignee<'a, T> {
+    nonce: u32,
+    payload: &'a T,
+    timestamp: u64,
+    user: &'a UserKey,
+}
+
+/// The current version of the wire format, stamped into every [`Signee`] on signing.
+///
+/// Version 1 adds [`Signee::version`] itself, which is required. Items signed before it can be
+/// brought in with [`migrate_legacy`].
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The version of items signed in the true canonical form of RFC8785, where keys are lexically
+/// sorted at every level, including the `typ` tag of payloads.
+///
+/// It is otherwise the same as [`PROTOCOL_VERSION`], and is stamped by
+/// [`WithSig::sign_canonical`] for [`Canonical`] payloads. Verifiers pick the canonical form by
+/// the version, so items of unknown payload types can still be verified.
+pub const SORTED_VERSION: u32 = 2;
+
+/// The version tagged onto items signed before [`Signee::version`] existed.
+///
+/// They verify under a legacy canonicalization path, where the `version` field is omitted from
+/// the canonical form, exactly as it was signed. No new items are signed in this version, so they
+/// are only accepted by [`WithSig::verify_signature`] for history, and never as live inputs.
+pub const LEGACY_VERSION: u32 = 0;
+
+/// Bring a stored item from before [`PROTOCOL_VERSION`] 1 into the current model, by tagging it as
+/// [`LEGACY_VERSION`] if it does not have a `version`. Items with a `version` are kept as-is.
+///
+/// The signature is verified (but not the timestamp, since migrated items are old), so the
+/// result is known to be authentic.
+pub fn migrate_legacy(mut item: serde_json::Value) -> anyhow::Result<WithSig<serde_json::Value>> {
+    let signee = item
+        .get_mut("signee")
+        .and_then(|v| v.as_object_mut())
+        .context("missing signee")?;
+    if !signee.contains_key("version") {
+        signee.insert("version".into(), LEGACY_VERSION.into());
+    }
+    let item = WithSig::<serde_json::Value>::try_from_value(item)?;
+    item.verify_signature()?;
+    Ok(item)
+}
+
+impl<T> Signee<T> {
+    /// Replace the timestamp with `now`, keeping everything else.
+    pub fn with_fresh_timestamp(self, now: u64) -> Self {
+        Self {
+            timestamp: now,
+            ..self
+        }
+    }
+}
+
+impl<T: Serialize> Signee<T> {
+    /// Get the canonical serialization, which is the input of the signature.
+    pub fn canonical_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.write_canonical(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Stream the canonical serialization into `w`, eg. a hasher.
+    ///
+    /// NB. Ed25519 signing (without prehashing) consumes the message twice, so [`WithSig::sign`]
+    /// and [`WithSig::verify`] still need the buffered [`Signee::canonical_bytes`].
+    pub fn write_canonical(&self, w: &mut impl io::Write) -> anyhow::Result<()> {
+        self.write_canonical_raw(w).context("failed to serialize")
+    }
+
+    fn write_canonical_raw(&self, w: &mut impl io::Write) -> serde_json::Result<()> {
+        if self.version == LEGACY_VERSION {
+            let legacy = LegacySignee {
+                nonce: self.nonce,
+                payload: &self.payload,
+                timestamp: self.timestamp,
+                user: &self.user,
+            };
+            serde_json::to_writer(w, &legacy)
+        } else if self.version == SORTED_VERSION {
+            // `serde_json::Map` is sorted by keys.
+            serde_json::to_writer(w, &serde_json::to_value(self)?)
+        } else {
+            serde_json::to_writer(w, self)
+        }
+    }
 }
 
 fn get_timestamp() -> u64 {
-    SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .expect("after UNIX epoch")
-        .as_secs()
+    timestamp_of(SystemTime::now())
+}
+
+/// Seconds since UNIX epoch. A misconfigured clock before the epoch gives 0 instead of
+/// panicking, and such items simply fail the timestamp check on verification.
+fn timestamp_of(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// The last second of year 9999, the maximum of RFC3339.
+const MAX_RFC3339_TIMESTAMP: u64 = 253_402_300_799;
+
+/// Format a timestamp in seconds since UNIX epoch as RFC3339 in UTC, eg.
+/// `2024-09-01T12:34:56Z`, for display only. Timestamps beyond year 9999 are clamped.
+///
+/// NB. Signed timestamps are always integers. This never affects signing.
+pub fn format_timestamp(ts: u64) -> String {
+    let time = SystemTime::UNIX_EPOCH + Duration::from_secs(ts.min(MAX_RFC3339_TIMESTAMP));
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+/// Parse an RFC3339 time in UTC, the output of [`format_timestamp`], into seconds since UNIX
+/// epoch. Fractional seconds are truncated.
+pub fn parse_timestamp(s: &str) -> anyhow::Result<u64> {
+    let time = humantime::parse_rfc3339(s).context("invalid RFC3339 time")?;
+    Ok(timestamp_of(time))
+}
+
+impl<T> WithSig<T> {
+    /// Seconds left at `now` before the item falls out of the [`TimestampPolicy::Fresh`] window
+    /// of `tolerance` seconds. It is fresh (by age) only if the result is positive, and the result
+    /// is zero or negative if already stale.
+    ///
+    /// NB. Timestamps too far in the future are also rejected by [`WithSig::verify`], which is not
+    /// reflected here.
+    pub fn seconds_until_stale(&self, now: u64, tolerance: u64) -> i64 {
+        let left = i128::from(self.signee.timestamp) + i128::from(tolerance) - i128::from(now);
+        left.clamp(i64::MIN.into(), i64::MAX.into()) as i64
+    }
 }
 
 impl<T: Serialize> WithSig<T> {
     pub fn sign(key: &SigningKey, rng: &mut impl RngCore, payload: T) -> anyhow::Result<Self> {
+        Self::sign_at(key, rng, payload, SystemTime::now())
+    }
+
+    /// Same as [`WithSig::sign`] but with the current time given as `now`.
+    pub fn sign_at(
+        key: &SigningKey,
+        rng: &mut impl RngCore,
+        payload: T,
+        now: SystemTime,
+    ) -> anyhow::Result<Self> {
         let signee = Signee {
             nonce: rng.next_u32(),
             payload,
-            timestamp: get_timestamp(),
-            user: UserKey(key.verifying_key().to_bytes()),
+            timestamp: timestamp_of(now),
+            user: UserKey::from_signing_key(key),
+            version: PROTOCOL_VERSION,
         };
-        let canonical_signee = serde_json::to_vec(&signee).context("failed to serialize")?;
+        Self::sign_signee(key, signee)
+    }
+
+    fn sign_signee(key: &SigningKey, signee: Signee<T>) -> anyhow::Result<Self> {
+        let canonical_signee = signee.canonical_bytes()?;
         let sig = key.try_sign(&canonical_signee)?.to_bytes();
         Ok(Self { sig, signee })
     }
 
-    pub fn verify(&self) -> anyhow::Result<()> {
+    /// Re-sign a previously signed item, eg. a draft, with the current timestamp and a new nonce.
+    /// The payload is kept as-is.
+    pub fn refresh(self, key: &SigningKey, rng: &mut impl RngCore) -> anyhow::Result<Self> {
         ensure!(
-            self.signee.timestamp.abs_diff(get_timestamp()) < TIMESTAMP_TOLERENCE,
-            "invalid timestamp"
+            self.signee.user == UserKey::from_signing_key(key),
+            "signing key mismatch",
         );
+        let mut signee = self.signee.with_fresh_timestamp(get_timestamp());
+        signee.nonce = rng.next_u32();
+        Self::sign_signee(key, signee)
+    }
+
+    /// Verify the timestamp freshness and the signature.
+    ///
+    /// The signature is checked with [`VerifyingKey::verify_strict`], which rejects
+    /// non-canonical (malleable) encodings of a valid signature, as well as weak public keys.
+    pub fn verify(&self) -> anyhow::Result<()> {
+        self.verify_with(&ProtocolLimits::default())
+    }
+
+    /// Same as [`WithSig::verify`] but with custom limits.
+    pub fn verify_with(&self, limits: &ProtocolLimits) -> anyhow::Result<()> {
+        self.check_timestamp(TimestampPolicy::Fresh, limits.timestamp_tolerance)?;
+        Ok(self.check_signature(false)?)
+    }
 
-        let canonical_signee = serde_json::to_vec(&self.signee).context("failed to serialize")?;
+    /// Same as [`WithSig::verify`] but with custom limits and timestamp policy.
+    pub fn verify_with_options(&self, opts: &VerifyOptions) -> Result<(), VerifyError> {
+        if !opts.skip_timestamp {
+            self.check_timestamp(opts.timestamp, opts.limits.timestamp_tolerance)?;
+        }
+        self.check_signature(false)
+    }
+
+    /// Verify under `opts` and get the payload only if it passes.
+    pub fn verified_payload(&self, opts: &VerifyOptions) -> Result<&T, VerifyError> {
+        self.verify_with_options(opts)?;
+        Ok(&self.signee.payload)
+    }
+
+    /// Get the canonical serialization of the whole envelope, including the signature, eg. for
+    /// hashing by audit tools.
+    ///
+    /// NB. This is not the signed input, which is [`Signee::canonical_bytes`].
+    pub fn canonical_envelope_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        serde_json::to_vec(self).context("failed to serialize")
+    }
+
+    /// Verify only the signature, without checking the timestamp freshness.
+    ///
+    /// This is for re-verification of historical items, so [`LEGACY_VERSION`] items are also
+    /// accepted. Live inputs should use [`WithSig::verify`] instead.
+    pub fn verify_signature(&self) -> anyhow::Result<()> {
+        Ok(self.check_signature(true)?)
+    }
+
+    fn check_timestamp(&self, policy: TimestampPolicy, tolerance: u64) -> Result<(), VerifyError> {
+        let (now, ts) = (get_timestamp(), self.signee.timestamp);
+        let diff = ts.abs_diff(now);
+        let expired_by = match policy {
+            TimestampPolicy::Fresh => diff.checked_sub(tolerance),
+            TimestampPolicy::Offline { max_age } => {
+                if ts >= now.saturating_add(tolerance) {
+                    Some(diff.saturating_sub(tolerance))
+                } else if ts < now && diff > max_age {
+                    Some(diff - max_age)
+                } else {
+                    None
+                }
+            }
+            TimestampPolicy::Informational => None,
+        };
+        match expired_by {
+            None => Ok(()),
+            Some(expired_by_secs) => Err(VerifyError::ExpiredTimestamp { expired_by_secs }),
+        }
+    }
+
+    fn check_signature(&self, allow_legacy: bool) -> Result<(), VerifyError> {
+        let version = self.signee.version;
+        let supported = match version {
+            PROTOCOL_VERSION | SORTED_VERSION => true,
+            LEGACY_VERSION => allow_legacy,
+            _ => false,
+        };
+        if !supported {
+            return Err(VerifyError::UnsupportedVersion(self.signee.version));
+        }
+        let mut canonical_signee = Vec::new();
+        self.signee
+            .write_canonical_raw(&mut canonical_signee)
+            .map_err(VerifyError::Serialize)?;
         let sig = Signature::from_bytes(&self.sig);
-        VerifyingKey::from_bytes(&self.signee.user.0)?.verify_strict(&canonical_signee, &sig)?;
-        Ok(())
+        VerifyingKey::from_bytes(&self.signee.user.0)
+            .map_err(|_| VerifyError::InvalidKey)?
+            .verify_strict(&canonical_signee, &sig)
+            .map_err(|_| VerifyError::InvalidSignature)
     }
 }
 
-// FIXME: `deny_unknown_fields` breaks this.
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(tag = "typ", rename = "chat")]
-pub struct ChatPayload {
-    pub room: Uuid,
-    pub text: String,
+/// Sign arbitrary bytes with an identity key, without the [`Signee`] envelope.
+///
+/// NB. There is no timestamp or nonce, so detached signatures never expire and are replayable.
+pub fn sign_detached(key: &SigningKey, msg: &[u8]) -> [u8; SIGNATURE_LENGTH] {
+    key.sign(msg).to_bytes()
 }
 
-pub type ChatItem = WithSig<ChatPayload>;
-
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(tag = "typ", rename = "create_room")]
-pub struct CreateRoomPayload {
-    pub attrs: RoomAttrs,
-    /// The initial member list. Besides invariants of `RoomMemberList`, this also must include the
-    /// room creator themselves, with the highest permission (-1).
-    pub members: RoomMemberList,
-    pub title: String,
+/// Verify a signature produced by [`sign_detached`].
+pub fn verify_detached(
+    user: &UserKey,
+    msg: &[u8],
+    sig: &[u8; SIGNATURE_LENGTH],
+) -> anyhow::Result<()> {
+    VerifyingKey::from_bytes(&user.0)?.verify_strict(msg, &Signature::from_bytes(sig))?;
+    Ok(())
 }
 
-/// A collection of room members, with these invariants:
-/// 1. Sorted by userkeys.
-/// 2. No duplicated users.
-#[derive(Debug, Deserialize)]
-#[serde(try_from = "Vec<RoomMember>")]
-pub struct RoomMemberList(pub Vec<RoomMember>);
+impl<T: DeserializeOwned> WithSig<T> {
+    /// Convert from an already parsed JSON value. The signature is not verified.
+    pub fn try_from_value(v: serde_json::Value) -> anyhow::Result<Self> {
+        serde_json::from_value(v).context("invalid signed payload")
+    }
+}
 
-impl Serialize for RoomMemberList {
-    fn serialize<S>(&self, ser: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        self.0.serialize(ser)
+impl<T: Serialize + DeserializeOwned + Validate> WithSig<T> {
+    /// Parse and verify an untrusted serialized item under `limits`, then [`Validate`] the
+    /// payload.
+    pub fn ingest(bytes: &[u8], limits: &ProtocolLimits) -> anyhow::Result<Self> {
+        ensure!(bytes.len() <= limits.max_frame_len, "item too large");
+        check_json_depth(bytes, limits.max_json_depth)?;
+        let this = serde_json::from_slice::<Self>(bytes).context("invalid signed payload")?;
+        this.verify_with(limits)?;
+        this.signee.payload.validate(limits)?;
+        this.signee
+            .payload
+            .validate_with_id(&MessageId::of(&this))?;
+        Ok(this)
     }
 }
 
-impl TryFrom<Vec<RoomMember>> for RoomMemberList {
-    type Error = &'static str;
+/// Parse and verify an untrusted serialized item of any payload type under `opts`, and return the
+/// author, eg. for relays not knowing all payload schemas. Unknown fields in the envelope are
+/// still rejected. The payload is not [`Validate`]d.
+///
+/// NB. The payload is parsed as [`AnyPayload`] rather than a plain [`serde_json::Value`], which
+/// would emit keys in a different order than the canonical form with `typ` first, and fail the
+/// signature check.
+pub fn verify_opaque(bytes: &[u8], opts: &VerifyOptions) -> anyhow::Result<UserKey> {
+    ensure!(bytes.len() <= opts.limits.max_frame_len, "item too large");
+    check_json_depth(bytes, opts.limits.max_json_depth)?;
+    let item =
+        serde_json::from_slice::<WithSig<AnyPayload>>(bytes).context("invalid signed payload")?;
+    item.verify_with_options(opts)?;
+    Ok(item.signee.user)
+}
 
-    fn try_from(members: Vec<RoomMember>) -> Result<Self, Self::Error> {
-        if members.windows(2).all(|w| w[0].user.0 < w[1].user.0) {
-            Ok(Self(members))
-        } else {
-            Err("unsorted or duplicated users")
+/// Reject serialized JSON `bytes` with objects or arrays nested deeper than `max_depth`, before
+/// parsing into recursive structures like [`UnknownPayload`] which may blow the stack.
+///
+/// This is a shallow scan without parsing, so the input still may be invalid JSON.
+pub fn check_json_depth(bytes: &[u8], max_depth: usize) -> anyhow::Result<()> {
+    let (mut depth, mut in_str, mut escaped) = (0usize, false, false);
+    for &b in bytes {
+        if in_str {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_str = false,
+                _ => {}
+            }
+            continue;
+        }
+        match b {
+            b'"' => in_str = true,
+            b'{' | b'[' => {
+                depth += 1;
+                ensure!(depth <= max_depth, "JSON nested too deep");
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
         }
     }
+    Ok(())
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct RoomMember {
-    pub permission: MemberPermission,
-    pub user: UserKey,
+/// Verify and [`Validate`] each of already parsed `items` independently, eg. messages synced
+/// from an offline client, and report results in the same order.
+pub fn ingest_many<T: Serialize + Validate>(
+    items: Vec<WithSig<T>>,
+    opts: &VerifyOptions,
+) -> Vec<Result<WithSig<T>, VerifyError>> {
+    items
+        .into_iter()
+        .map(|item| {
+            item.verify_with_options(opts)?;
+            let payload = &item.signee.payload;
+            payload
+                .validate(&opts.limits)
+                .and_then(|()| payload.validate_with_id(&MessageId::of(&item)))
+                .map_err(VerifyError::InvalidPayload)?;
+            Ok(item)
+        })
+        .collect()
 }
 
-/// Proof of room membership for read-access.
+/// A signed item with the author sealed, for metadata-minimizing clients.
 ///
-/// TODO: Should we use JWT here instead?
+/// The signature and the author key are encrypted together with a symmetric room key shared
+/// among room members. The signature still covers the full [`Signee`] including the author, so a
+/// member can unseal it back to a verifiable [`WithSig`]. Without the signature, knowing the keys
+/// of all room members does not help to find out the author.
+///
+/// Threat model: this only hides the author from observers of the envelope not holding the room
+/// key, including the server if it does not hold it. Such a server cannot authorize the author
+/// nor verify the signature either. Timing, size and the rest of the envelope are still visible
+/// to observers, and every holder of the room key can recover the author.
+#[cfg(feature = "sealed-sender")]
 #[derive(Debug, Serialize, Deserialize)]
-#[serde(tag = "typ", rename = "auth")]
-pub struct AuthPayload {}
+#[serde(deny_unknown_fields)]
+pub struct SealedSig<T> {
+    #[serde(with = "hex::serde")]
+    pub seal_nonce: [u8; 24],
+    /// The encrypted signature followed by the encrypted author key.
+    #[serde(with = "hex::serde")]
+    pub sealed: Vec<u8>,
+    pub signee: SealedSignee<T>,
+}
 
+/// The [`Signee`] without the author.
+#[cfg(feature = "sealed-sender")]
 #[derive(Debug, Serialize, Deserialize)]
-#[serde(deny_unknown_fields, tag = "typ", rename_all = "snake_case")]
-pub enum RoomAdminPayload {
-    AddMember {
-        permission: MemberPermission,
-        room: Uuid,
-        user: UserKey,
-    },
-    // TODO: CRUD
+#[serde(deny_unknown_fields)]
+pub struct SealedSignee<T> {
+    pub nonce: u32,
+    pub payload: T,
+    pub timestamp: u64,
+    pub version: u32,
 }
 
-bitflags! {
-    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-    pub struct ServerPermission: u64 {
-        const CREATE_ROOM = 1 << 0;
-
-        const ALL = !0;
+#[cfg(feature = "sealed-sender")]
+impl<T> SealedSig<T> {
+    pub fn seal(item: WithSig<T>, room_key: &[u8; 32], rng: &mut impl RngCore) -> Self {
+        let mut seal_nonce = [0u8; 24];
+        rng.fill_bytes(&mut seal_nonce);
+        let mut plain = [0u8; SIGNATURE_LENGTH + PUBLIC_KEY_LENGTH];
+        plain[..SIGNATURE_LENGTH].copy_from_slice(&item.sig);
+        plain[SIGNATURE_LENGTH..].copy_from_slice(&item.signee.user.0);
+        let sealed = XChaCha20Poly1305::new(room_key.into())
+            .encrypt(XNonce::from_slice(&seal_nonce), &plain[..])
+            .expect("encryption cannot fail");
+        Self {
+            seal_nonce,
+            sealed,
+            signee: SealedSignee {
+                nonce: item.signee.nonce,
+                payload: item.signee.payload,
+                timestamp: item.signee.timestamp,
+                version: item.signee.version,
+            },
+        }
     }
 
-    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-    pub struct MemberPermission: u64 {
-        const POST_CHAT = 1 << 0;
-        const ADD_MEMBER = 1 << 1;
-
-        const ALL = !0;
+    /// Recover the [`WithSig`] with the author. The signature is not verified.
+    pub fn unseal(self, room_key: &[u8; 32]) -> anyhow::Result<WithSig<T>> {
+        let plain = XChaCha20Poly1305::new(room_key.into())
+            .decrypt(XNonce::from_slice(&self.seal_nonce), &self.sealed[..])
+            .ok()
+            .context("failed to unseal the author")?;
+        ensure!(
+            plain.len() == SIGNATURE_LENGTH + PUBLIC_KEY_LENGTH,
+            "invalid sealed length"
+        );
+        let (sig, user) = plain.split_at(SIGNATURE_LENGTH);
+        Ok(WithSig {
+            sig: sig.try_into().expect("length checked"),
+            signee: Signee {
+                nonce: self.signee.nonce,
+                payload: self.signee.payload,
+                timestamp: self.signee.timestamp,
+                user: UserKey(user.try_into().expect("length checked")),
+                version: self.signee.version,
+            },
+        })
     }
+}
 
-    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
-    pub struct RoomAttrs: u64 {
-        const PUBLIC_READABLE = 1 << 0;
+/// The identifier of a signed item, derived as the SHA-256 hash of its signature.
+///
+/// It is available to both clients and servers without any server-side assignment, and is unique
+/// because signatures are deterministic and non-malleable under [`WithSig::verify`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct MessageId(#[serde(with = "hex::serde")] pub [u8; 32]);
 
-        const _ = !0;
+impl MessageId {
+    pub fn of<T>(item: &WithSig<T>) -> Self {
+        Self(Sha256::digest(item.sig).into())
     }
 }
 
-impl_serde_for_bitflags!(ServerPermission);
-impl_serde_for_bitflags!(MemberPermission);
-impl_serde_for_bitflags!(RoomAttrs);
-
-mod sql_impl {
-    use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
-    use rusqlite::{Result, ToSql};
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
 
-    use super::*;
+// FIXME: `deny_unknown_fields` breaks this.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "typ", rename = "chat")]
+pub struct ChatPayload {
+    pub room: Uuid,
+    /// The optional per-room sequence number of the author, for ordered and gap-detectable
+    /// delivery. If used, it starts from 0 and increases by 1 for each message of the same author
+    /// in the same room. It is omitted in the canonical form when absent, so legacy messages keep
+    /// their signatures.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+    pub text: String,
+}
 
-    impl ToSql for UserKey {
-        fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
-            // TODO: Extensive key format?
-            self.0.to_sql()
-        }
+impl Validate for ChatPayload {
+    fn validate(&self, limits: &ProtocolLimits) -> anyhow::Result<()> {
+        ensure!(!self.text.is_empty(), "empty text");
+        ensure!(self.text.len() <= limits.max_text_len, "text too long");
+        Ok(())
     }
+}
 
-    impl FromSql for UserKey {
-        fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
-            let rawkey = <[u8; PUBLIC_KEY_LENGTH]>::column_result(value)?;
-            let key = VerifyingKey::from_bytes(&rawkey)
-                .map_err(|err| FromSqlError::Other(format!("invalid pubkey: {err}").into()))?;
-            Ok(UserKey(key.to_bytes()))
+impl ReferencedKeys for ChatPayload {
+    fn referenced_keys(&self) -> Vec<UserKey> {
+        mentioned_keys(&self.text)
+    }
+}
+
+impl ChatPayload {
+    /// Split the text into lowercased words for full-text indexing, using Unicode word
+    /// boundaries (UAX #29). Mentions (`@` followed by a hex-encoded user key) are skipped.
+    pub fn search_tokens(&self) -> Vec<String> {
+        let mut tokens = Vec::new();
+        let mut push_words = |s: &str| tokens.extend(s.unicode_words().map(str::to_lowercase));
+        let mut rest = 0;
+        for range in mention_ranges(&self.text) {
+            push_words(&self.text[rest..range.start]);
+            rest = range.end;
         }
+        push_words(&self.text[rest..]);
+        tokens
     }
+}
 
-    macro_rules! impl_u64_flag {
-        ($($name:ident),*) => {
-            $(
-                impl ToSql for $name {
-                    fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
-                        // Cast out the sign.
-                        Ok((self.bits() as i64).into())
-                    }
-                }
+/// Byte ranges of mentions in the form `@<hex user key>` in `text`.
+fn mention_ranges(text: &str) -> impl Iterator<Item = std::ops::Range<usize>> + '_ {
+    const LEN: usize = 1 + PUBLIC_KEY_LENGTH * 2;
+    text.match_indices('@').filter_map(move |(start, _)| {
+        let end = start + LEN;
+        let key = text.get(start + 1..end)?;
+        let at_boundary = !text[end..].starts_with(|c: char| c.is_alphanumeric());
+        (at_boundary && key.bytes().all(|b| b.is_ascii_hexdigit())).then_some(start..end)
+    })
+}
 
-                impl FromSql for $name {
-                    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
-                        // Cast out the sign.
-                        i64::column_result(value).map(|v| $name::from_bits_retain(v as u64))
-                    }
-                }
-            )*
-        };
+/// Keys of mentions in `text`, see [`mention_ranges`].
+fn mentioned_keys(text: &str) -> Vec<UserKey> {
+    let keys = mention_ranges(text)
+        .map(|range| {
+            let mut key = [0u8; PUBLIC_KEY_LENGTH];
+            hex::decode_to_slice(&text[range.start + 1..range.end], &mut key)
+                .expect("checked to be hex");
+            UserKey(key)
+        })
+        .collect();
+    dedup_keys(keys)
+}
+
+pub type ChatItem = WithSig<ChatPayload>;
+
+impl ChatItem {
+    /// Days since UNIX epoch of the signed timestamp, as an index key for retention, eg.
+    /// `DELETE ... WHERE day_bucket < ?`.
+    pub fn day_bucket(&self) -> u32 {
+        (self.signee.timestamp / 86400)
+            .try_into()
+            .unwrap_or(u32::MAX)
     }
+}
 
-    impl_u64_flag!(ServerPermission, MemberPermission, RoomAttrs);
+/// A part of a long chat message split into `total` chunks of the same `group`. Each chunk is
+/// signed independently, and the full text is the concatenation of all chunks ordered by `index`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "typ", rename = "chat_chunk")]
+pub struct ChatChunk {
+    pub group: Uuid,
+    pub index: u16,
+    pub room: Uuid,
+    pub text: String,
+    pub total: u16,
 }
 
-#[cfg(test)]
-mod tests {
-    use std::fmt::Write;
+pub type ChatChunkItem = WithSig<ChatChunk>;
 
-    #[derive(Default)]
-    struct Visitor {
-        errors: String,
+impl Validate for ChatChunk {
+    fn validate(&self, limits: &ProtocolLimits) -> anyhow::Result<()> {
+        ensure!(self.index < self.total, "chunk index out of range");
+        ensure!(!self.text.is_empty(), "empty text");
+        ensure!(self.text.len() <= limits.max_text_len, "text too long");
+        Ok(())
     }
+}
 
-    impl<'ast> syn::visit::Visit<'ast> for Visitor {
-        fn visit_fields_named(&mut self, i: &'ast syn::FieldsNamed) {
-            let fields = i
-                .named
+impl ReferencedKeys for ChatChunk {
+    fn referenced_keys(&self) -> Vec<UserKey> {
+        mentioned_keys(&self.text)
+    }
+}
+
+impl ChatChunk {
+    /// Reassemble the full text from all chunks of a group, in any order.
+    pub fn reassemble(chunks: &[ChatChunk]) -> anyhow::Result<String> {
+        let first = chunks.first().context("no chunks")?;
+        ensure!(first.total != 0, "invalid total chunk count");
+        ensure!(
+            chunks
                 .iter()
-                .flat_map(|f| f.ident.clone())
-                .map(|i| i.to_string())
-                .collect::<Vec<_>>();
-            if !fields.windows(2).all(|w| w[0] < w[1]) {
-                writeln!(self.errors, "unsorted fields: {fields:?}").unwrap();
-            }
+                .all(|c| c.group == first.group && c.room == first.room && c.total == first.total),
+            "chunks from different groups",
+        );
+        let mut sorted = chunks.iter().collect::<Vec<_>>();
+        sorted.sort_by_key(|c| c.index);
+        for (expect, c) in (0..first.total).zip(&sorted) {
+            ensure!(c.index >= expect, "duplicated chunk {}", c.index);
+            ensure!(c.index == expect, "missing chunk {expect}");
         }
+        ensure!(
+            sorted.len() == usize::from(first.total),
+            "expecting {} chunks, got {}",
+            first.total,
+            sorted.len(),
+        );
+        Ok(sorted.iter().map(|c| &*c.text).collect())
     }
+}
 
-    #[test]
-    fn canonical_fields_sorted() {
-        let src = std::fs::read_to_string(file!()).unwrap();
-        let file = syn::parse_file(&src).unwrap();
+/// Replace the text of an earlier chat message `target` from the same author.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "typ", rename = "edit_chat")]
+pub struct EditChatPayload {
+    pub room: Uuid,
+    pub target: MessageId,
+    pub text: String,
+}
 
-        let mut v = Visitor::default();
-        syn::visit::visit_file(&mut v, &file);
-        if !v.errors.is_empty() {
-            panic!("{}", v.errors);
+pub type EditChatItem = WithSig<EditChatPayload>;
+
+impl Validate for EditChatPayload {
+    fn validate(&self, limits: &ProtocolLimits) -> anyhow::Result<()> {
+        ensure!(!self.text.is_empty(), "empty text");
+        ensure!(self.text.len() <= limits.max_text_len, "text too long");
+        Ok(())
+    }
+
+    fn validate_with_id(&self, id: &MessageId) -> anyhow::Result<()> {
+        ensure!(self.target != *id, "edit targets itself");
+        Ok(())
+    }
+}
+
+impl ReferencedKeys for EditChatPayload {
+    fn referenced_keys(&self) -> Vec<UserKey> {
+        mentioned_keys(&self.text)
+    }
+}
+
+/// Forward or quote a chat message `original`, possibly from another room, into `room`.
+///
+/// The original signed item is carried as-is, so its author can still be verified.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "typ", rename = "forward")]
+pub struct ForwardPayload {
+    pub original: Box<ChatItem>,
+    pub room: Uuid,
+}
+
+pub type ForwardItem = WithSig<ForwardPayload>;
+
+impl Validate for ForwardPayload {
+    /// The original is only checked for its signature and payload, but not the timestamp, since
+    /// forwarded messages are usually old.
+    fn validate(&self, limits: &ProtocolLimits) -> anyhow::Result<()> {
+        (|| {
+            self.original.verify_signature()?;
+            self.original.signee.payload.validate(limits)
+        })()
+        .context("invalid original message")
+    }
+}
+
+impl ReferencedKeys for ForwardPayload {
+    fn referenced_keys(&self) -> Vec<UserKey> {
+        self.original.referenced_keys()
+    }
+}
+
+/// Delete an earlier chat message `target`, which is then replaced by a
+/// [`ServerEvent::Tombstone`].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "typ", rename = "delete_chat")]
+pub struct DeleteChatPayload {
+    pub room: Uuid,
+    pub target: MessageId,
+}
+
+pub type DeleteChatItem = WithSig<DeleteChatPayload>;
+
+impl Validate for DeleteChatPayload {
+    fn validate_with_id(&self, id: &MessageId) -> anyhow::Result<()> {
+        ensure!(self.target != *id, "deletion targets itself");
+        Ok(())
+    }
+}
+
+impl ReferencedKeys for DeleteChatPayload {}
+
+/// Block (or unblock, if `block` is false) messages from user `target` for the signer.
+///
+/// Blocking is personal. It only affects delivery to the signer, and is kept in sync across
+/// their devices by the server.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "typ", rename = "block")]
+pub struct BlockPayload {
+    pub block: bool,
+    pub target: UserKey,
+}
+
+pub type BlockItem = WithSig<BlockPayload>;
+
+impl BlockPayload {
+    /// Apply to the set of blocked users. Returns whether the set is changed.
+    pub fn apply(&self, blocked: &mut HashSet<UserKey>) -> bool {
+        if self.block {
+            blocked.insert(self.target.clone())
+        } else {
+            blocked.remove(&self.target)
+        }
+    }
+}
+
+impl Validate for BlockPayload {}
+
+impl ReferencedKeys for BlockPayload {
+    fn referenced_keys(&self) -> Vec<UserKey> {
+        vec![self.target.clone()]
+    }
+}
+
+/// Authorize a subkey `device_key` to act as the signer on one of their devices, until
+/// `expires_at` (in seconds since UNIX epoch, exclusive) if any.
+///
+/// It is signed by the primary identity key. See [`DeviceRegistrationItem::identity_of`].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "typ", rename = "device_reg")]
+pub struct DeviceRegistrationPayload {
+    pub device_key: UserKey,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// A human-readable name of the device.
+    pub label: String,
+}
+
+pub type DeviceRegistrationItem = WithSig<DeviceRegistrationPayload>;
+
+impl DeviceRegistrationPayload {
+    /// The maximum length of [`DeviceRegistrationPayload::label`] in bytes.
+    pub const MAX_LABEL_LEN: usize = 64;
+}
+
+impl DeviceRegistrationItem {
+    /// Map an item signed by the registered device back to the primary identity, at `now` (in
+    /// seconds since UNIX epoch).
+    ///
+    /// The registration signature is verified, but not its timestamp, since registrations are
+    /// long-lived. The signature of `item` itself is not verified here.
+    pub fn identity_of<T>(&self, item: &WithSig<T>, now: u64) -> anyhow::Result<&UserKey> {
+        self.verify_signature()?;
+        let reg = &self.signee.payload;
+        ensure!(
+            item.signee.user == reg.device_key,
+            "item is not signed by the registered device",
+        );
+        if let Some(expires_at) = reg.expires_at {
+            ensure!(now < expires_at, "device registration expired");
+        }
+        Ok(&self.signee.user)
+    }
+}
+
+impl Validate for DeviceRegistrationPayload {
+    fn validate(&self, _limits: &ProtocolLimits) -> anyhow::Result<()> {
+        ensure!(!self.label.is_empty(), "empty label");
+        ensure!(self.label.len() <= Self::MAX_LABEL_LEN, "label too long");
+        Ok(())
+    }
+}
+
+impl ReferencedKeys for DeviceRegistrationPayload {
+    fn referenced_keys(&self) -> Vec<UserKey> {
+        vec![self.device_key.clone()]
+    }
+}
+
+/// An out-of-band invitation to join `room` with `permission`, valid until `expires_at` (in
+/// seconds since UNIX epoch, exclusive).
+///
+/// It should be signed by a room admin. See [`crate::room::redeem`].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "typ", rename = "invite")]
+pub struct InviteToken {
+    pub expires_at: u64,
+    pub permission: MemberPermission,
+    pub room: Uuid,
+}
+
+impl Validate for InviteToken {}
+
+impl ReferencedKeys for InviteToken {}
+
+/// An unguessable capability to read a [`RoomAttrs::PUBLIC_READABLE`] room without a key, eg.
+/// for share links.
+///
+/// It is passed in the URL fragment as `<room>.<hex secret>`, so it is not sent to servers in
+/// requests for the page itself. The server keeps the secret of each room and checks it with
+/// [`ReadCapability::validate`].
+#[derive(Debug, Clone)]
+pub struct ReadCapability {
+    pub room: Uuid,
+    pub secret: [u8; 32],
+}
+
+impl ReadCapability {
+    /// Generate a capability of `room` with a random secret.
+    pub fn generate(room: Uuid, rng: &mut impl RngCore) -> Self {
+        let mut secret = [0u8; 32];
+        rng.fill_bytes(&mut secret);
+        Self { room, secret }
+    }
+
+    pub fn to_url_fragment(&self) -> String {
+        format!("{}.{}", self.room, hex::encode(self.secret))
+    }
+
+    /// Parse the output of [`ReadCapability::to_url_fragment`], with or without the leading `#`.
+    pub fn from_url_fragment(s: &str) -> anyhow::Result<Self> {
+        let s = s.strip_prefix('#').unwrap_or(s);
+        let (room, secret) = s.split_once('.').context("missing separator")?;
+        let room = room.parse().context("invalid room id")?;
+        let mut buf = [0u8; 32];
+        hex::decode_to_slice(secret, &mut buf).context("invalid secret")?;
+        Ok(Self { room, secret: buf })
+    }
+
+    /// Check the secret against `stored_secret` of the room, in constant time.
+    pub fn validate(&self, stored_secret: &[u8; 32]) -> bool {
+        subtle::ConstantTimeEq::ct_eq(&self.secret[..], &stored_secret[..]).into()
+    }
+}
+
+/// Pin or unpin (if `pin` is false) a message `target` in the room. This requires
+/// [`MemberPermission::UPDATE_ROOM`].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "typ", rename = "pin")]
+pub struct PinPayload {
+    pub pin: bool,
+    pub room: Uuid,
+    pub target: MessageId,
+}
+
+pub type PinItem = WithSig<PinPayload>;
+
+impl Validate for PinPayload {}
+
+impl ReferencedKeys for PinPayload {}
+
+/// An acknowledgement that all messages in the room up to and including `up_to` are delivered to
+/// the signer's client, but not necessarily read. See [`ReadReceiptPayload`] for reads.
+///
+/// Like other receipts, it only moves forward. See [`crate::room::ReceiptMarks`].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "typ", rename = "delivered")]
+pub struct DeliveryReceiptPayload {
+    pub room: Uuid,
+    pub up_to: MessageId,
+}
+
+pub type DeliveryReceiptItem = WithSig<DeliveryReceiptPayload>;
+
+impl Validate for DeliveryReceiptPayload {}
+
+impl ReferencedKeys for DeliveryReceiptPayload {}
+
+/// An acknowledgement that all messages in the room up to and including `up_to` are read by the
+/// signer. It implies the corresponding [`DeliveryReceiptPayload`].
+///
+/// It only moves forward, with marks separate from delivery receipts. See
+/// [`crate::room::ReceiptMarks`].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "typ", rename = "read_receipt")]
+pub struct ReadReceiptPayload {
+    pub room: Uuid,
+    pub up_to: MessageId,
+}
+
+pub type ReadReceiptItem = WithSig<ReadReceiptPayload>;
+
+impl Validate for ReadReceiptPayload {}
+
+impl ReferencedKeys for ReadReceiptPayload {}
+
+/// A report of an abusive message `target` to moderators of the room.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "typ", rename = "report")]
+pub struct ReportPayload {
+    pub reason: String,
+    pub room: Uuid,
+    pub target: MessageId,
+}
+
+pub type ReportItem = WithSig<ReportPayload>;
+
+impl ReportPayload {
+    pub const MAX_REASON_LEN: usize = 1024;
+}
+
+impl Validate for ReportPayload {
+    fn validate(&self, _limits: &ProtocolLimits) -> anyhow::Result<()> {
+        ensure!(!self.reason.is_empty(), "empty reason");
+        ensure!(self.reason.len() <= Self::MAX_REASON_LEN, "reason too long");
+        Ok(())
+    }
+}
+
+impl ReferencedKeys for ReportPayload {}
+
+/// A lightweight online status ping. Its freshness is naturally bounded by the timestamp
+/// tolerance of [`WithSig::verify`].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "typ", rename = "presence")]
+pub struct PresencePayload {
+    pub room: Uuid,
+    pub status: PresenceStatus,
+    /// An optional short custom status, eg. an emoji. It is omitted in the canonical form when
+    /// absent, to keep minimal pings small.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_text: Option<String>,
+}
+
+pub type PresenceItem = WithSig<PresencePayload>;
+
+impl PresencePayload {
+    /// The maximum length of [`PresencePayload::status_text`] in bytes.
+    pub const MAX_STATUS_TEXT_LEN: usize = 128;
+
+    /// Strip control characters from a user-provided status text, as is required by
+    /// [`Validate`]. The length is not checked.
+    pub fn sanitize_status_text(text: &str) -> String {
+        text.chars().filter(|c| !c.is_control()).collect()
+    }
+}
+
+impl Validate for PresencePayload {
+    fn validate(&self, _limits: &ProtocolLimits) -> anyhow::Result<()> {
+        if let Some(text) = &self.status_text {
+            ensure!(
+                text.len() <= Self::MAX_STATUS_TEXT_LEN,
+                "status text too long"
+            );
+            ensure!(
+                !text.chars().any(char::is_control),
+                "control characters in status text"
+            );
+        }
+        Ok(())
+    }
+}
+
+impl ReferencedKeys for PresencePayload {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    Offline,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "typ", rename = "create_room")]
+pub struct CreateRoomPayload {
+    pub attrs: RoomAttrs,
+    /// The initial member list. Besides invariants of `RoomMemberList`, this also must include the
+    /// room creator themselves, with [`MemberPermission::OWNER`].
+    pub members: RoomMemberList,
+    pub title: String,
+}
+
+impl CreateRoomPayload {
+    pub fn member_count(&self) -> usize {
+        self.members.0.len()
+    }
+
+    pub fn is_member(&self, user: &UserKey) -> bool {
+        self.members.contains(user)
+    }
+
+    /// Check `creator` is included in the member list with [`MemberPermission::OWNER`].
+    pub fn check_creator(&self, creator: &UserKey) -> Result<(), MemberListError> {
+        match self.members.get(creator) {
+            Some(m) if m.permission.is_owner() => Ok(()),
+            _ => Err(MemberListError::CreatorMissing),
         }
     }
 }
+
+impl Validate for CreateRoomPayload {
+    fn validate(&self, limits: &ProtocolLimits) -> anyhow::Result<()> {
+        validate_title(&self.title)?;
+        ensure!(
+            self.members.0.len() <= limits.max_members,
+            "too many members"
+        );
+        Ok(())
+    }
+}
+
+impl ReferencedKeys for CreateRoomPayload {
+    fn referenced_keys(&self) -> Vec<UserKey> {
+        self.members.0.iter().map(|m| m.user.clone()).collect()
+    }
+}
+
+/// A request to join a room, authenticated by the joiner.
+///
+/// It grants nothing by itself. The server or an admin with [`MemberPermission::ADD_MEMBER`]
+/// approves it by issuing a [`RoomAdminPayload::AddMember`] for the joiner.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "typ", rename = "join_request")]
+pub struct JoinRequestPayload {
+    pub room: Uuid,
+}
+
+pub type JoinRequestItem = WithSig<JoinRequestPayload>;
+
+impl Validate for JoinRequestPayload {}
+
+impl ReferencedKeys for JoinRequestPayload {}
+
+/// A request to leave a room, authenticated by the leaving member.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "typ", rename = "leave")]
+pub struct LeavePayload {
+    pub room: Uuid,
+}
+
+pub type LeaveItem = WithSig<LeavePayload>;
+
+impl Validate for LeavePayload {}
+
+impl ReferencedKeys for LeavePayload {}
+
+/// Distribution of a symmetric room key to members, signed by the distributing admin.
+///
+/// Recipients are sorted by their keys without duplicates.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "typ", rename = "room_key")]
+pub struct RoomKeyPayload {
+    pub recipients: Vec<RoomKeyRecipient>,
+    pub room: Uuid,
+}
+
+pub type RoomKeyItem = WithSig<RoomKeyPayload>;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RoomKeyRecipient {
+    /// The room key encrypted to the X25519 key of `user`, derived by [`UserKey::to_montgomery`].
+    #[serde(with = "hex::serde")]
+    pub sealed_key: Vec<u8>,
+    pub user: UserKey,
+}
+
+impl Validate for RoomKeyPayload {
+    fn validate(&self, limits: &ProtocolLimits) -> anyhow::Result<()> {
+        ensure!(!self.recipients.is_empty(), "no recipients");
+        ensure!(
+            self.recipients.len() <= limits.max_members,
+            "too many recipients"
+        );
+        ensure!(
+            self.recipients.windows(2).all(|w| w[0].user < w[1].user),
+            "unsorted or duplicated recipients",
+        );
+        Ok(())
+    }
+}
+
+impl ReferencedKeys for RoomKeyPayload {
+    fn referenced_keys(&self) -> Vec<UserKey> {
+        self.recipients.iter().map(|r| r.user.clone()).collect()
+    }
+}
+
+/// A collection of room members, with these invariants:
+/// 1. Sorted by userkeys.
+/// 2. No duplicated users.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "Vec<RoomMember>")]
+pub struct RoomMemberList(pub Vec<RoomMember>);
+
+impl RoomMemberList {
+    /// Build a member list from members in any order. Fails on duplicated users.
+    ///
+    /// Wire inputs should still be parsed strictly via [`TryFrom`], since the signed form must be
+    /// canonical.
+    pub fn from_unsorted(mut members: Vec<RoomMember>) -> anyhow::Result<Self> {
+        members.sort_by(|a, b| a.user.cmp(&b.user));
+        Ok(Self::try_from(members)?)
+    }
+
+    pub fn get(&self, user: &UserKey) -> Option<&RoomMember> {
+        let idx = self.0.binary_search_by(|m| m.user.cmp(user)).ok()?;
+        Some(&self.0[idx])
+    }
+
+    /// Remove a member. Fails if the user is not a member, or is the last member, since an empty
+    /// list is invalid. See [`MemberListError::Empty`].
+    pub fn remove(&mut self, user: &UserKey) -> anyhow::Result<RoomMember> {
+        match self.0.binary_search_by(|m| m.user.cmp(user)) {
+            Ok(_) if self.0.len() == 1 => Err(MemberListError::Empty.into()),
+            Ok(idx) => Ok(self.0.remove(idx)),
+            Err(_) => anyhow::bail!("user {user} is not a member"),
+        }
+    }
+
+    pub fn contains(&self, user: &UserKey) -> bool {
+        self.get(user).is_some()
+    }
+
+    /// Insert a new member at its sorted position. Fails if the user is already a member.
+    pub fn insert(&mut self, member: RoomMember) -> anyhow::Result<()> {
+        match self.0.binary_search_by(|m| m.user.cmp(&member.user)) {
+            Ok(_) => anyhow::bail!("user {} is already a member", member.user),
+            Err(idx) => self.0.insert(idx, member),
+        }
+        Ok(())
+    }
+
+    /// SHA-256 of the canonical (JSON) serialization, for cheap comparison of member lists.
+    ///
+    /// The member list is always sorted, so the same set of members gives the same hash.
+    pub fn canonical_hash(&self) -> [u8; 32] {
+        let mut h = Sha256::new();
+        serde_json::to_writer(&mut h, self).expect("serialization cannot fail");
+        h.finalize().into()
+    }
+
+    /// Export as tab-separated `user\tpermission` lines, one per member, in the canonical order.
+    /// Users are in hex and permissions use the [`Display`](fmt::Display) format of
+    /// [`MemberPermission`].
+    pub fn to_tsv(&self) -> String {
+        let mut out = String::new();
+        for m in &self.0 {
+            // Writing to `String` never fails.
+            let _ = writeln!(out, "{}\t{}", m.user, m.permission);
+        }
+        out
+    }
+
+    /// Parse the output of [`RoomMemberList::to_tsv`]. Empty lines are ignored.
+    ///
+    /// The same invariants as [`TryFrom`] are checked, so members must be sorted and unique.
+    pub fn from_tsv(s: &str) -> anyhow::Result<Self> {
+        let members = s
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(i, line)| {
+                (|| {
+                    let (user, perm) = line.split_once('\t').context("missing tab")?;
+                    anyhow::Ok(RoomMember {
+                        permission: perm
+                            .parse()
+                            .map_err(|err| anyhow::anyhow!("invalid permission: {err}"))?,
+                        user: user.parse()?,
+                    })
+                })()
+                .with_context(|| format!("invalid line #{}", i + 1))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self::try_from(members)?)
+    }
+}
+
+/// Members are always serialized sorted by userkeys, even if the list is constructed unsorted
+/// through the public field, so the signed form (eg. of [`CreateRoomPayload`]) is canonical.
+impl Serialize for RoomMemberList {
+    fn serialize<S>(&self, ser: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        if self.0.is_sorted_by(|a, b| a.user <= b.user) {
+            return self.0.serialize(ser);
+        }
+        let mut sorted = self.0.iter().collect::<Vec<_>>();
+        sorted.sort_by(|a, b| a.user.cmp(&b.user));
+        sorted.serialize(ser)
+    }
+}
+
+impl TryFrom<Vec<RoomMember>> for RoomMemberList {
+    type Error = MemberListError;
+
+    fn try_from(members: Vec<RoomMember>) -> Result<Self, Self::Error> {
+        if members.is_empty() {
+            return Err(MemberListError::Empty);
+        }
+        for w in members.windows(2) {
+            match w[0].user.cmp(&w[1].user) {
+                Ordering::Less => {}
+                Ordering::Equal => return Err(MemberListError::Duplicate(w[0].user.clone())),
+                Ordering::Greater => return Err(MemberListError::Unsorted),
+            }
+        }
+        Ok(Self(members))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemberListError {
+    /// Members are not sorted by their keys.
+    Unsorted,
+    /// The user occurs more than once.
+    Duplicate(UserKey),
+    /// There is no member at all.
+    Empty,
+    /// The room creator is not a member with [`MemberPermission::OWNER`].
+    CreatorMissing,
+}
+
+impl fmt::Display for MemberListError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unsorted => f.write_str("unsorted users"),
+            Self::Duplicate(user) => write!(f, "duplicated user {user}"),
+            Self::Empty => f.write_str("empty member list"),
+            Self::CreatorMissing => f.write_str("room creator is not an owner"),
+        }
+    }
+}
+
+impl std::error::Error for MemberListError {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomMember {
+    pub permission: MemberPermission,
+    pub user: UserKey,
+}
+
+/// A human-readable room name, like `general` in `#general`.
+///
+/// Aliases are normalized to lowercase on parsing, and consist of 1 to `MAX_LEN` characters
+/// from ASCII alphanumerics, `-`, `_` and `.`. A single leading `#` is accepted and stripped on
+/// parsing, but is not part of the alias itself.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RoomAlias(Box<str>);
+
+impl RoomAlias {
+    pub const MAX_LEN: usize = 64;
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RoomAlias {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for RoomAlias {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.strip_prefix('#').unwrap_or(s);
+        if s.is_empty() {
+            return Err("empty alias");
+        }
+        if s.len() > Self::MAX_LEN {
+            return Err("alias too long");
+        }
+        if !s
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+        {
+            return Err("invalid character in alias");
+        }
+        Ok(Self(s.to_ascii_lowercase().into()))
+    }
+}
+
+impl TryFrom<String> for RoomAlias {
+    type Error = &'static str;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<RoomAlias> for String {
+    fn from(alias: RoomAlias) -> Self {
+        alias.0.into()
+    }
+}
+
+/// A change of room members.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MemberDiff {
+    pub added: Vec<RoomMember>,
+    pub removed: Vec<UserKey>,
+}
+
+/// An event pushed from the server to real-time clients.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "typ", rename_all = "snake_case")]
+pub enum ServerEvent {
+    NewChat(ChatItem),
+    MemberChanged {
+        diff: MemberDiff,
+        room: Uuid,
+    },
+    Presence(PresenceItem),
+    /// An item is deleted.
+    Tombstone {
+        room: Uuid,
+        target: MessageId,
+    },
+}
+
+/// Proof of room membership for read-access.
+///
+/// TODO: Should we use JWT here instead?
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "typ", rename = "auth")]
+pub struct AuthPayload {}
+
+impl Validate for AuthPayload {}
+
+/// The result of [`verify_auth_token_with_grace`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFreshness {
+    /// Within the usual timestamp tolerance.
+    Fresh,
+    /// Already expired, but within the grace window. It is accepted, but the client should sign
+    /// a new one.
+    NeedsRefresh,
+}
+
+/// Verify a signed auth token at `now` (in seconds since UNIX epoch), accepting tokens expired
+/// by at most `grace` beyond [`ProtocolLimits::timestamp_tolerance`], so users are not logged out
+/// in the middle of an action.
+///
+/// Timestamps in the future still get no grace.
+pub fn verify_auth_token_with_grace(
+    token: &WithSig<AuthPayload>,
+    limits: &ProtocolLimits,
+    now: u64,
+    grace: std::time::Duration,
+) -> Result<AuthFreshness, VerifyError> {
+    let (ts, tolerance) = (token.signee.timestamp, limits.timestamp_tolerance);
+    let diff = ts.abs_diff(now);
+    let freshness = if diff < tolerance {
+        AuthFreshness::Fresh
+    } else if ts < now && diff < tolerance.saturating_add(grace.as_secs()) {
+        AuthFreshness::NeedsRefresh
+    } else {
+        let window = if ts < now {
+            tolerance.saturating_add(grace.as_secs())
+        } else {
+            tolerance
+        };
+        return Err(VerifyError::ExpiredTimestamp {
+            expired_by_secs: diff - window,
+        });
+    };
+    token.check_signature(false)?;
+    Ok(freshness)
+}
+
+impl ReferencedKeys for AuthPayload {}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, tag = "typ", rename_all = "snake_case")]
+pub enum RoomAdminPayload {
+    /// Apply multiple operations on the same room atomically, in order, under one signature.
+    Batch {
+        ops: AdminBatch,
+        room: Uuid,
+    },
+    AddMember {
+        permission: MemberPermission,
+        room: Uuid,
+        user: UserKey,
+    },
+    RemoveMember {
+        room: Uuid,
+        user: UserKey,
+    },
+    /// Change the room title. This requires [`MemberPermission::UPDATE_ROOM`].
+    SetTitle {
+        room: Uuid,
+        title: String,
+    },
+    /// Change the longer room description, or remove it if empty. This requires
+    /// [`MemberPermission::UPDATE_ROOM`].
+    SetDescription {
+        description: String,
+        room: Uuid,
+    },
+    /// Delete all messages of the room with signed timestamps before `up_to` (in seconds since
+    /// UNIX epoch). This requires [`MemberPermission::UPDATE_ROOM`].
+    ///
+    /// NB. This is irreversible. There is no way to restore the deleted messages, and messages
+    /// signed before `up_to` but appended later are deleted as well. See
+    /// [`crate::room::verify_room_log`].
+    ClearHistory {
+        room: Uuid,
+        up_to: u64,
+    },
+    /// Replace the room attributes. This requires [`MemberPermission::UPDATE_ROOM`].
+    ///
+    /// Unlike on creation, unknown attributes are rejected, since they cannot be authorized.
+    UpdateRoomAttrs {
+        attrs: RoomAttrs,
+        room: Uuid,
+    },
+    // TODO: CRUD
+}
+
+impl RoomAdminPayload {
+    pub fn room(&self) -> Uuid {
+        match self {
+            Self::Batch { room, .. }
+            | Self::AddMember { room, .. }
+            | Self::RemoveMember { room, .. }
+            | Self::SetTitle { room, .. }
+            | Self::SetDescription { room, .. }
+            | Self::ClearHistory { room, .. }
+            | Self::UpdateRoomAttrs { room, .. } => *room,
+        }
+    }
+}
+
+impl Validate for RoomAdminPayload {
+    fn validate(&self, limits: &ProtocolLimits) -> anyhow::Result<()> {
+        match self {
+            Self::Batch { ops, room } => {
+                ensure!(!ops.0.is_empty(), "empty batch");
+                ensure!(ops.0.len() <= limits.max_batch_ops, "too many operations");
+                ops.0.iter().try_for_each(|op| {
+                    ensure!(op.room() == *room, "operation on a different room");
+                    op.validate(limits)
+                })
+            }
+            Self::AddMember { .. } | Self::RemoveMember { .. } | Self::ClearHistory { .. } => {
+                Ok(())
+            }
+            Self::SetTitle { title, .. } => validate_title(title),
+            Self::UpdateRoomAttrs { attrs, .. } => attrs.validate_known(),
+            Self::SetDescription { description, .. } => {
+                ensure!(
+                    description.len() <= MAX_DESCRIPTION_LEN,
+                    "description too long"
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+impl ReferencedKeys for RoomAdminPayload {
+    fn referenced_keys(&self) -> Vec<UserKey> {
+        match self {
+            Self::Batch { ops, .. } => {
+                dedup_keys(ops.0.iter().flat_map(|op| op.referenced_keys()).collect())
+            }
+            Self::AddMember { user, .. } | Self::RemoveMember { user, .. } => vec![user.clone()],
+            Self::ClearHistory { .. }
+            | Self::SetDescription { .. }
+            | Self::SetTitle { .. }
+            | Self::UpdateRoomAttrs { .. } => Vec::new(),
+        }
+    }
+}
+
+/// Operations of a [`RoomAdminPayload::Batch`], with no nested batches. The number of operations
+/// is limited by [`ProtocolLimits::max_batch_ops`] on validation.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(try_from = "Vec<RoomAdminPayload>")]
+pub struct AdminBatch(pub Vec<RoomAdminPayload>);
+
+impl TryFrom<Vec<RoomAdminPayload>> for AdminBatch {
+    type Error = &'static str;
+
+    fn try_from(ops: Vec<RoomAdminPayload>) -> Result<Self, Self::Error> {
+        if ops
+            .iter()
+            .any(|op| matches!(op, RoomAdminPayload::Batch { .. }))
+        {
+            return Err("nested batch");
+        }
+        Ok(Self(ops))
+    }
+}
+
+pub const MAX_TITLE_LEN: usize = 256;
+
+/// Check a room title is non-empty and at most `MAX_TITLE_LEN` bytes.
+pub fn validate_title(title: &str) -> anyhow::Result<()> {
+    ensure!(!title.is_empty(), "empty title");
+    ensure!(title.len() <= MAX_TITLE_LEN, "title too long");
+    Ok(())
+}
+
+/// The maximum length in bytes of a room description.
+pub const MAX_DESCRIPTION_LEN: usize = 1 << 10;
+
+/// Any payload, dispatched by its `typ`, with unrecognized types kept as [`UnknownPayload`].
+///
+/// This allows relaying and verifying signed items of types introduced after this
+/// implementation.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum AnyPayload {
+    Auth(AuthPayload),
+    Block(BlockPayload),
+    Chat(ChatPayload),
+    ChatChunk(ChatChunk),
+    CreateRoom(CreateRoomPayload),
+    DeleteChat(DeleteChatPayload),
+    DeliveryReceipt(DeliveryReceiptPayload),
+    DeviceRegistration(DeviceRegistrationPayload),
+    EditChat(EditChatPayload),
+    Forward(ForwardPayload),
+    Invite(InviteToken),
+    JoinRequest(JoinRequestPayload),
+    Leave(LeavePayload),
+    Pin(PinPayload),
+    Presence(PresencePayload),
+    ReadReceipt(ReadReceiptPayload),
+    Report(ReportPayload),
+    RoomAdmin(RoomAdminPayload),
+    RoomKey(RoomKeyPayload),
+    Unknown(UnknownPayload),
+}
+
+impl<'de> Deserialize<'de> for AnyPayload {
+    fn deserialize<D: serde::Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        use serde_json::from_value;
+
+        let v = serde_json::Value::deserialize(de)?;
+        let typ = v
+            .get("typ")
+            .and_then(|typ| typ.as_str())
+            .ok_or_else(|| D::Error::missing_field("typ"))?;
+        match typ {
+            "auth" => from_value(v).map(Self::Auth),
+            "block" => from_value(v).map(Self::Block),
+            "chat" => from_value(v).map(Self::Chat),
+            "chat_chunk" => from_value(v).map(Self::ChatChunk),
+            "create_room" => from_value(v).map(Self::CreateRoom),
+            "delete_chat" => from_value(v).map(Self::DeleteChat),
+            "delivered" => from_value(v).map(Self::DeliveryReceipt),
+            "device_reg" => from_value(v).map(Self::DeviceRegistration),
+            "edit_chat" => from_value(v).map(Self::EditChat),
+            "forward" => from_value(v).map(Self::Forward),
+            "invite" => from_value(v).map(Self::Invite),
+            "join_request" => from_value(v).map(Self::JoinRequest),
+            "leave" => from_value(v).map(Self::Leave),
+            "pin" => from_value(v).map(Self::Pin),
+            "presence" => from_value(v).map(Self::Presence),
+            "read_receipt" => from_value(v).map(Self::ReadReceipt),
+            "report" => from_value(v).map(Self::Report),
+            "add_member" | "batch" | "clear_history" | "remove_member" | "set_description"
+            | "set_title" | "update_room_attrs" => from_value(v).map(Self::RoomAdmin),
+            "room_key" => from_value(v).map(Self::RoomKey),
+            _ => from_value(v).map(Self::Unknown),
+        }
+        .map_err(D::Error::custom)
+    }
+}
+
+impl AnyPayload {
+    fn as_referenced_keys(&self) -> Option<&dyn ReferencedKeys> {
+        Some(match self {
+            Self::Auth(p) => p,
+            Self::Block(p) => p,
+            Self::Chat(p) => p,
+            Self::ChatChunk(p) => p,
+            Self::CreateRoom(p) => p,
+            Self::DeleteChat(p) => p,
+            Self::DeliveryReceipt(p) => p,
+            Self::DeviceRegistration(p) => p,
+            Self::EditChat(p) => p,
+            Self::Forward(p) => p,
+            Self::Invite(p) => p,
+            Self::JoinRequest(p) => p,
+            Self::Leave(p) => p,
+            Self::Pin(p) => p,
+            Self::Presence(p) => p,
+            Self::ReadReceipt(p) => p,
+            Self::Report(p) => p,
+            Self::RoomAdmin(p) => p,
+            Self::RoomKey(p) => p,
+            Self::Unknown(_) => return None,
+        })
+    }
+
+    fn as_validate(&self) -> Option<&dyn Validate> {
+        Some(match self {
+            Self::Auth(p) => p,
+            Self::Block(p) => p,
+            Self::Chat(p) => p,
+            Self::ChatChunk(p) => p,
+            Self::CreateRoom(p) => p,
+            Self::DeleteChat(p) => p,
+            Self::DeliveryReceipt(p) => p,
+            Self::DeviceRegistration(p) => p,
+            Self::EditChat(p) => p,
+            Self::Forward(p) => p,
+            Self::Invite(p) => p,
+            Self::JoinRequest(p) => p,
+            Self::Leave(p) => p,
+            Self::Pin(p) => p,
+            Self::Presence(p) => p,
+            Self::ReadReceipt(p) => p,
+            Self::Report(p) => p,
+            Self::RoomAdmin(p) => p,
+            Self::RoomKey(p) => p,
+            Self::Unknown(_) => return None,
+        })
+    }
+}
+
+impl Validate for AnyPayload {
+    fn validate(&self, limits: &ProtocolLimits) -> anyhow::Result<()> {
+        self.as_validate().map_or(Ok(()), |p| p.validate(limits))
+    }
+
+    fn validate_with_id(&self, id: &MessageId) -> anyhow::Result<()> {
+        self.as_validate()
+            .map_or(Ok(()), |p| p.validate_with_id(id))
+    }
+}
+
+impl ReferencedKeys for AnyPayload {
+    fn referenced_keys(&self) -> Vec<UserKey> {
+        self.as_referenced_keys()
+            .map(|p| p.referenced_keys())
+            .unwrap_or_default()
+    }
+}
+
+/// A payload of an unrecognized `typ`, with all other fields kept as-is.
+///
+/// It serializes `typ` first followed by other fields in lexical order, which is the canonical
+/// form of every payload of [`PROTOCOL_VERSION`], so the signature of the original item can still
+/// be verified. Items of [`SORTED_VERSION`] are fully sorted when computing the canonical form, see
+/// [`Signee::canonical_bytes`].
+#[derive(Debug, Deserialize)]
+pub struct UnknownPayload {
+    #[serde(flatten)]
+    pub rest: serde_json::Map<String, serde_json::Value>,
+    pub typ: String,
+}
+
+impl Serialize for UnknownPayload {
+    fn serialize<S: serde::Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let mut map = ser.serialize_map(Some(1 + self.rest.len()))?;
+        map.serialize_entry("typ", &self.typ)?;
+        for (k, v) in &self.rest {
+            map.serialize_entry(k, v)?;
+        }
+        map.end()
+    }
+}
+
+/// A payload serialized in the true canonical form of RFC8785, with keys lexically sorted at
+/// every level, including the `typ` tag.
+///
+/// New payload types should be wrapped in this, and be signed by [`WithSig::sign_canonical`] in
+/// [`SORTED_VERSION`], so that verifiers not knowing `T` can tell the form. It deserializes the
+/// same as `T`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Canonical<T>(pub T);
+
+impl<T: Serialize> Serialize for Canonical<T> {
+    fn serialize<S: serde::Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        // `serde_json::Map` is sorted by keys.
+        serde_json::to_value(&self.0)
+            .map_err(serde::ser::Error::custom)?
+            .serialize(ser)
+    }
+}
+
+impl<T: Serialize> WithSig<Canonical<T>> {
+    /// Same as [`WithSig::sign`] but in [`SORTED_VERSION`].
+    pub fn sign_canonical(
+        key: &SigningKey,
+        rng: &mut impl RngCore,
+        payload: T,
+    ) -> anyhow::Result<Self> {
+        let signee = Signee {
+            nonce: rng.next_u32(),
+            payload: Canonical(payload),
+            timestamp: get_timestamp(),
+            user: UserKey::from_signing_key(key),
+            version: SORTED_VERSION,
+        };
+        Self::sign_signee(key, signee)
+    }
+}
+
+impl<T: Validate> Validate for Canonical<T> {
+    fn validate(&self, limits: &ProtocolLimits) -> anyhow::Result<()> {
+        self.0.validate(limits)
+    }
+
+    fn validate_with_id(&self, id: &MessageId) -> anyhow::Result<()> {
+        self.0.validate_with_id(id)
+    }
+}
+
+impl<T: ReferencedKeys> ReferencedKeys for Canonical<T> {
+    fn referenced_keys(&self) -> Vec<UserKey> {
+        self.0.referenced_keys()
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ServerPermission: u64 {
+        const CREATE_ROOM = 1 << 0;
+
+        const ALL = !0;
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct MemberPermission: u64 {
+        const POST_CHAT = 1 << 0;
+        const ADD_MEMBER = 1 << 1;
+        const UPDATE_ROOM = 1 << 2;
+        const REMOVE_MEMBER = 1 << 3;
+
+        const ALL = !0;
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct RoomAttrs: u64 {
+        const PUBLIC_READABLE = 1 << 0;
+
+        const _ = !0;
+    }
+}
+
+impl MemberPermission {
+    /// The highest permission with all bits set, which the room creator must have. It is stored as
+    /// `-1` in SQL.
+    pub const OWNER: Self = Self::ALL;
+
+    pub fn is_owner(&self) -> bool {
+        *self == Self::OWNER
+    }
+
+    /// Get the bits `(added, removed)` when changing from this permission to `new`, eg. for audit
+    /// logs.
+    pub fn diff(&self, new: MemberPermission) -> (MemberPermission, MemberPermission) {
+        (new.difference(*self), self.difference(new))
+    }
+}
+
+impl RoomAttrs {
+    /// Get the bits not corresponding to any named attribute.
+    ///
+    /// NB. `RoomAttrs::all()` includes all bits to keep unknown attributes as-is.
+    pub fn unknown_bits(&self) -> Self {
+        let known = Self::FLAGS
+            .iter()
+            .filter(|f| f.is_named())
+            .fold(Self::empty(), |acc, f| acc | *f.value());
+        self.difference(known)
+    }
+
+    /// Reject attributes not understood by this implementation, for servers in strict mode.
+    pub fn validate_known(&self) -> anyhow::Result<()> {
+        let unknown = self.unknown_bits();
+        ensure!(
+            unknown.is_empty(),
+            "unknown room attributes: {:#x}",
+            unknown.bits(),
+        );
+        Ok(())
+    }
+
+    /// Get the snake_case tags of named attributes set, eg. `["public_readable"]`, for APIs.
+    ///
+    /// NB. Unknown bits have no tag and are omitted. See [`RoomAttrs::unknown_bits`].
+    pub fn to_tags(&self) -> Vec<String> {
+        Self::FLAGS
+            .iter()
+            .filter(|f| f.is_named() && self.contains(*f.value()))
+            .map(|f| f.name().to_ascii_lowercase())
+            .collect()
+    }
+
+    /// Parse the output of [`RoomAttrs::to_tags`]. Fails on unknown tags.
+    pub fn from_tags(tags: &[&str]) -> anyhow::Result<Self> {
+        tags.iter().try_fold(Self::empty(), |acc, tag| {
+            let flag = Self::FLAGS
+                .iter()
+                .find(|f| f.is_named() && f.name().to_ascii_lowercase() == *tag)
+                .with_context(|| format!("unknown room attribute tag {tag:?}"))?;
+            Ok(acc | *flag.value())
+        })
+    }
+}
+
+/// Render flags as `|`-separated names, eg. `POST_CHAT|ADD_MEMBER`. Flags equal to a single named
+/// flag, eg. `ALL`, are rendered as that name. Unnamed bits are rendered in hex, and the empty set
+/// is `0x0`.
+fn write_flags<F: Flags<Bits = u64>>(flags: &F, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let bits = flags.bits();
+    let named = F::FLAGS.iter().filter(|fl| fl.is_named());
+    if let Some(fl) = named.clone().find(|fl| fl.value().bits() == bits) {
+        return f.write_str(fl.name());
+    }
+    let mut rest = bits;
+    let mut sep = "";
+    for fl in named {
+        let fl_bits = fl.value().bits();
+        if fl_bits & !bits == 0 && fl_bits & rest != 0 {
+            write!(f, "{sep}{}", fl.name())?;
+            rest &= !fl_bits;
+            sep = "|";
+        }
+    }
+    if rest != 0 || bits == 0 {
+        write!(f, "{sep}{rest:#x}")?;
+    }
+    Ok(())
+}
+
+/// Parse the format of [`write_flags`]. Whitespace around names is allowed.
+fn parse_flags<F: Flags<Bits = u64>>(s: &str) -> Result<F, bitflags::parser::ParseError> {
+    use bitflags::parser::ParseError;
+
+    s.split('|')
+        .map(str::trim)
+        .try_fold(F::empty(), |acc, part| {
+            let flag = if part.is_empty() {
+                return Err(ParseError::empty_flag());
+            } else if let Some(hex) = part.strip_prefix("0x") {
+                u64::from_str_radix(hex, 16)
+                    .map(F::from_bits_retain)
+                    .map_err(|_| ParseError::invalid_hex_flag(part))?
+            } else {
+                F::from_name(part).ok_or_else(|| ParseError::invalid_named_flag(part))?
+            };
+            Ok(acc.union(flag))
+        })
+}
+
+macro_rules! impl_display_for_bitflags {
+    ($ty:ty) => {
+        impl fmt::Display for $ty {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write_flags(self, f)
+            }
+        }
+
+        impl FromStr for $ty {
+            type Err = bitflags::parser::ParseError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                parse_flags(s)
+            }
+        }
+    };
+}
+
+impl_display_for_bitflags!(ServerPermission);
+impl_display_for_bitflags!(MemberPermission);
+impl_display_for_bitflags!(RoomAttrs);
+
+impl_serde_for_bitflags!(ServerPermission);
+impl_serde_for_bitflags!(MemberPermission);
+impl_serde_for_bitflags!(RoomAttrs);
+
+/// A wrapper storing flags in SQL as a minimal-length LEB128 varint BLOB, instead of a full
+/// `INTEGER`.
+///
+/// Usual flags with only low bits set take one byte, while `ALL` takes the worst case of 10
+/// bytes. Non-minimal encodings are rejected on read, so each value has exactly one stored form.
+#[cfg(feature = "sql-varint")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VarintFlags<F>(pub F);
+
+/// Hex (de)serialization of fixed-size byte arrays, reporting the expected and actual length on
+/// mismatch, unlike `hex::serde`.
+mod hex_array {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer, const N: usize>(
+        v: &[u8; N],
+        ser: S,
+    ) -> Result<S::Ok, S::Error> {
+        hex::serde::serialize(v, ser)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        de: D,
+    ) -> Result<[u8; N], D::Error> {
+        decode(&String::deserialize(de)?)
+    }
+
+    pub fn decode<E: Error, const N: usize>(s: &str) -> Result<[u8; N], E> {
+        if s.len() != N * 2 {
+            return Err(E::custom(format_args!(
+                "invalid length: expected {N} bytes ({} hex digits), got {} hex digits",
+                N * 2,
+                s.len(),
+            )));
+        }
+        let mut buf = [0u8; N];
+        hex::decode_to_slice(s, &mut buf).map_err(E::custom)?;
+        Ok(buf)
+    }
+}
+
+/// Hex (de)serialization of optional bytes, either `Option<[u8; N]>` or `Option<Vec<u8>>`.
+///
+/// `None` is (de)serialized as `null`. To omit it in the canonical form instead, use with
+/// `#[serde(default, skip_serializing_if = "Option::is_none", with = "hex_opt")]`.
+pub mod hex_opt {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    /// Byte containers supported by [`hex_opt`](self).
+    pub trait HexBytes: AsRef<[u8]> + Sized {
+        fn decode<E: Error>(s: &str) -> Result<Self, E>;
+    }
+
+    impl<const N: usize> HexBytes for [u8; N] {
+        fn decode<E: Error>(s: &str) -> Result<Self, E> {
+            super::hex_array::decode(s)
+        }
+    }
+
+    impl HexBytes for Vec<u8> {
+        fn decode<E: Error>(s: &str) -> Result<Self, E> {
+            hex::decode(s).map_err(E::custom)
+        }
+    }
+
+    pub fn serialize<S: Serializer, T: HexBytes>(v: &Option<T>, ser: S) -> Result<S::Ok, S::Error> {
+        match v {
+            Some(v) => ser.serialize_some(&hex::encode(v)),
+            None => ser.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, T: HexBytes>(
+        de: D,
+    ) -> Result<Option<T>, D::Error> {
+        Option::<String>::deserialize(de)?
+            .map(|s| T::decode(&s))
+            .transpose()
+    }
+}
+
+mod sql_impl {
+    use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, Type, ValueRef};
+    use rusqlite::{Result, Row, ToSql};
+
+    use super::*;
+
+    impl ToSql for UserKey {
+        fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
+            // TODO: Extensive key format?
+            self.0.to_sql()
+        }
+    }
+
+    impl FromSql for UserKey {
+        fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+            let rawkey = <[u8; PUBLIC_KEY_LENGTH]>::column_result(value)?;
+            let key = VerifyingKey::from_bytes(&rawkey)
+                .map_err(|err| FromSqlError::Other(format!("invalid pubkey: {err}").into()))?;
+            Ok(UserKey(key.to_bytes()))
+        }
+    }
+
+    macro_rules! impl_u64_flag {
+        ($($name:ident),*) => {
+            $(
+                impl ToSql for $name {
+                    fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
+                        // Cast out the sign.
+                        Ok((self.bits() as i64).into())
+                    }
+                }
+
+                impl FromSql for $name {
+                    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+                        // Cast out the sign.
+                        i64::column_result(value).map(|v| $name::from_bits_retain(v as u64))
+                    }
+                }
+            )*
+        };
+    }
+
+    impl_u64_flag!(ServerPermission, MemberPermission, RoomAttrs);
+
+    #[cfg(feature = "sql-varint")]
+    impl<F: Flags<Bits = u64>> ToSql for VarintFlags<F> {
+        fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
+            let mut bits = self.0.bits();
+            let mut buf = Vec::with_capacity(10);
+            loop {
+                let byte = (bits & 0x7F) as u8;
+                bits >>= 7;
+                if bits == 0 {
+                    buf.push(byte);
+                    break;
+                }
+                buf.push(byte | 0x80);
+            }
+            Ok(buf.into())
+        }
+    }
+
+    #[cfg(feature = "sql-varint")]
+    impl<F: Flags<Bits = u64>> FromSql for VarintFlags<F> {
+        fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+            let invalid = |msg: &str| FromSqlError::Other(format!("invalid varint: {msg}").into());
+            let buf = value.as_blob()?;
+            let (&last, init) = buf.split_last().ok_or_else(|| invalid("empty"))?;
+            if last & 0x80 != 0 || init.iter().any(|b| b & 0x80 == 0) {
+                return Err(invalid("unterminated"));
+            }
+            if last == 0 && !init.is_empty() {
+                return Err(invalid("not minimal"));
+            }
+            let mut bits = 0u64;
+            for (i, &byte) in buf.iter().enumerate() {
+                let chunk = u64::from(byte & 0x7F);
+                let shift = 7 * i as u32;
+                if shift >= 64 || (chunk << shift) >> shift != chunk {
+                    return Err(invalid("overflow"));
+                }
+                bits |= chunk << shift;
+            }
+            Ok(Self(F::from_bits_retain(bits)))
+        }
+    }
+
+    impl WithSig<ChatPayload> {
+        /// The columns used by [`WithSig::to_sql_params`] and [`WithSig::from_sql_row`]. `room`,
+        /// `user` and `sig` are BLOBs, and `text` is kept alone as TEXT for full-text indexing.
+        pub const SQL_COLUMNS: &'static str =
+            "`room`, `user`, `timestamp`, `nonce`, `version`, `sig`, `sequence`, `text`";
+
+        /// Named parameters `:room`, `:user` and so on, for each column of
+        /// [`WithSig::SQL_COLUMNS`].
+        pub fn to_sql_params(&self) -> [(&'static str, &dyn ToSql); 8] {
+            let signee = &self.signee;
+            [
+                (":room", &signee.payload.room),
+                (":user", &signee.user),
+                (":timestamp", &signee.timestamp),
+                (":nonce", &signee.nonce),
+                (":version", &signee.version),
+                (":sig", &self.sig),
+                (":sequence", &signee.payload.sequence),
+                (":text", &signee.payload.text),
+            ]
+        }
+
+        /// Reconstruct a chat item from a row containing [`WithSig::SQL_COLUMNS`]. If `verify` is
+        /// set, the signature is also checked, but not the timestamp.
+        pub fn from_sql_row(row: &Row<'_>, verify: bool) -> Result<Self> {
+            let item = Self {
+                sig: row.get("sig")?,
+                signee: Signee {
+                    nonce: row.get("nonce")?,
+                    payload: ChatPayload {
+                        room: row.get("room")?,
+                        sequence: row.get("sequence")?,
+                        text: row.get("text")?,
+                    },
+                    timestamp: row.get("timestamp")?,
+                    user: row.get("user")?,
+                    version: row.get("version")?,
+                },
+            };
+            if verify {
+                item.verify_signature().map_err(|err| {
+                    let idx = row.as_ref().column_index("sig").unwrap_or_default();
+                    rusqlite::Error::FromSqlConversionFailure(idx, Type::Blob, err.into())
+                })?;
+            }
+            Ok(item)
+        }
+    }
+
+    impl StoredItem<ChatPayload> {
+        /// [`WithSig::SQL_COLUMNS`] with an additional INTEGER `received_at`.
+        // NB. Sync with `WithSig::SQL_COLUMNS`.
+        pub const SQL_COLUMNS: &'static str =
+            "`room`, `user`, `timestamp`, `nonce`, `version`, `sig`, `sequence`, `text`, `received_at`";
+
+        /// Named parameters for each column of [`StoredItem::SQL_COLUMNS`].
+        pub fn to_sql_params(&self) -> [(&'static str, &dyn ToSql); 9] {
+            let [a, b, c, d, e, f, g, h] = self.item.to_sql_params();
+            [a, b, c, d, e, f, g, h, (":received_at", &self.received_at)]
+        }
+
+        /// Reconstruct from a row containing [`StoredItem::SQL_COLUMNS`]. See
+        /// [`WithSig::from_sql_row`] for `verify`.
+        pub fn from_sql_row(row: &Row<'_>, verify: bool) -> Result<Self> {
+            Ok(Self {
+                item: WithSig::from_sql_row(row, verify)?,
+                received_at: row.get("received_at")?,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Write;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Visitor {
+        errors: String,
+    }
+
+    impl<'ast> syn::visit::Visit<'ast> for Visitor {
+        fn visit_fields_named(&mut self, i: &'ast syn::FieldsNamed) {
+            let fields = i
+                .named
+                .iter()
+                .flat_map(|f| f.ident.clone())
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>();
+            if !fields.windows(2).all(|w| w[0] < w[1]) {
+                writeln!(self.errors, "unsorted fields: {fields:?}").unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn canonical_fields_sorted() {
+        let src = std::fs::read_to_string(file!()).unwrap();
+        let file = syn::parse_file(&src).unwrap();
+
+        let mut v = Visitor::default();
+        syn::visit::visit_file(&mut v, &file);
+        if !v.errors.is_empty() {
+            panic!("{}", v.errors);
+        }
+    }
+
+    /// Check the actually emitted JSON, which must have keys in lexical order at every level,
+    /// except that the `typ` tag always comes first.
+    #[test]
+    fn canonical_emitted_key_order() {
+        let user = UserKey([1; 32]);
+        let room = Uuid::nil();
+
+        let mut chat = ChatPayload {
+            room,
+            sequence: None,
+            text: "hi".into(),
+        };
+        assert_eq!(
+            serde_json::to_string(&chat).unwrap(),
+            format!(r#"{{"typ":"chat","room":"{room}","text":"hi"}}"#),
+        );
+        chat.sequence = Some(3);
+        assert_eq!(
+            serde_json::to_string(&chat).unwrap(),
+            format!(r#"{{"typ":"chat","room":"{room}","sequence":3,"text":"hi"}}"#),
+        );
+
+        let create = CreateRoomPayload {
+            attrs: RoomAttrs::PUBLIC_READABLE,
+            members: RoomMemberList(vec![RoomMember {
+                permission: MemberPermission::POST_CHAT,
+                user: user.clone(),
+            }]),
+            title: "t".into(),
+        };
+        assert_eq!(
+            serde_json::to_string(&create).unwrap(),
+            format!(
+                r#"{{"typ":"create_room","attrs":1,"members":[{{"permission":1,"user":"{user}"}}],"title":"t"}}"#
+            ),
+        );
+
+        let signee = Signee {
+            nonce: 42,
+            payload: chat,
+            timestamp: 1234,
+            user: user.clone(),
+            version: PROTOCOL_VERSION,
+        };
+        assert_eq!(
+            String::from_utf8(signee.canonical_bytes().unwrap()).unwrap(),
+            format!(
+                r#"{{"nonce":42,"payload":{{"typ":"chat","room":"{room}","sequence":3,"text":"hi"}},"timestamp":1234,"user":"{user}","version":1}}"#
+            ),
+        );
+    }
+
+    #[test]
+    fn canonical_sorted_typ() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        #[serde(tag = "typ", rename = "new")]
+        struct NewPayload {
+            alpha: u32,
+            nested: Nested,
+            zeta: String,
+        }
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        #[serde(tag = "typ", rename = "nested")]
+        struct Nested {
+            a: u32,
+        }
+
+        let payload = Canonical(NewPayload {
+            alpha: 1,
+            nested: Nested { a: 2 },
+            zeta: "z".into(),
+        });
+        let json = serde_json::to_string(&payload).unwrap();
+        assert_eq!(
+            json,
+            r#"{"alpha":1,"nested":{"a":2,"typ":"nested"},"typ":"new","zeta":"z"}"#,
+        );
+        assert_eq!(
+            serde_json::from_str::<Canonical<NewPayload>>(&json).unwrap(),
+            payload
+        );
+
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let signed = WithSig::sign_canonical(&key, &mut rand::rngs::OsRng, payload.0).unwrap();
+        assert_eq!(signed.signee.version, SORTED_VERSION);
+        let bytes = String::from_utf8(signed.signee.canonical_bytes().unwrap()).unwrap();
+        assert!(bytes.contains(r#""payload":{"alpha":1,"#), "{bytes}");
+        signed.verify().unwrap();
+
+        // Verifiers not knowing the payload type pick the form by the version.
+        let json = serde_json::to_vec(&signed).unwrap();
+        assert_eq!(
+            verify_opaque(&json, &VerifyOptions::default()).unwrap(),
+            UserKey::from_signing_key(&key),
+        );
+        let any = serde_json::from_slice::<WithSig<AnyPayload>>(&json).unwrap();
+        assert!(matches!(any.signee.payload, AnyPayload::Unknown(_)));
+        any.verify().unwrap();
+    }
+
+    #[test]
+    fn room_alias() {
+        let alias = "#General-2.0_x".parse::<RoomAlias>().unwrap();
+        assert_eq!(alias.as_str(), "general-2.0_x");
+        assert_eq!(alias.to_string().parse::<RoomAlias>().unwrap(), alias);
+        assert_eq!(
+            serde_json::from_str::<RoomAlias>(r#""Foo""#).unwrap(),
+            "foo".parse().unwrap(),
+        );
+
+        let too_long = "a".repeat(RoomAlias::MAX_LEN + 1);
+        for invalid in ["", "#", "##a", "a b", "café", "a/b", &too_long] {
+            assert!(invalid.parse::<RoomAlias>().is_err(), "{invalid:?}");
+        }
+        assert!(serde_json::from_str::<RoomAlias>(r#""a b""#).is_err());
+    }
+
+    #[test]
+    fn member_list_insert() {
+        let member = |b: u8| RoomMember {
+            permission: MemberPermission::POST_CHAT,
+            user: UserKey([b; PUBLIC_KEY_LENGTH]),
+        };
+        let mut list = RoomMemberList(vec![member(1), member(3)]);
+        list.insert(member(2)).unwrap();
+        list.insert(member(0)).unwrap();
+        list.insert(member(3)).unwrap_err();
+        let users = list.0.iter().map(|m| m.user.0[0]).collect::<Vec<_>>();
+        assert_eq!(users, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn member_list_remove_last() {
+        let member = |b: u8| RoomMember {
+            permission: MemberPermission::POST_CHAT,
+            user: UserKey([b; PUBLIC_KEY_LENGTH]),
+        };
+        let mut list = RoomMemberList(vec![member(1), member(2)]);
+        list.remove(&member(0).user).unwrap_err();
+        list.remove(&member(1).user).unwrap();
+        let err = list.remove(&member(2).user).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MemberListError>(),
+            Some(&MemberListError::Empty)
+        );
+        // The list stays valid.
+        assert_eq!(list.0.len(), 1);
+        let json = serde_json::to_string(&list).unwrap();
+        serde_json::from_str::<RoomMemberList>(&json).unwrap();
+    }
+
+    #[test]
+    fn presence_serde() {
+        let room = Uuid::nil();
+        for (status, name) in [
+            (PresenceStatus::Online, "online"),
+            (PresenceStatus::Away, "away"),
+            (PresenceStatus::Offline, "offline"),
+        ] {
+            let json = serde_json::to_string(&PresencePayload {
+                room,
+                status,
+                status_text: None,
+            })
+            .unwrap();
+            assert_eq!(
+                json,
+                format!(r#"{{"typ":"presence","room":"{room}","status":"{name}"}}"#),
+            );
+            let back = serde_json::from_str::<PresencePayload>(&json).unwrap();
+            assert_eq!(back.status, status);
+        }
+        assert!(serde_json::from_str::<PresenceStatus>(r#""Online""#).is_err());
+
+        let presence = PresencePayload {
+            room,
+            status: PresenceStatus::Online,
+            status_text: Some("🎉 party".into()),
+        };
+        let json = serde_json::to_string(&presence).unwrap();
+        assert_eq!(
+            json,
+            format!(
+                r#"{{"typ":"presence","room":"{room}","status":"online","status_text":"🎉 party"}}"#
+            ),
+        );
+        let back = serde_json::from_str::<PresencePayload>(&json).unwrap();
+        assert_eq!(back.status_text.as_deref(), Some("🎉 party"));
+    }
+
+    #[test]
+    fn presence_status_text() {
+        let limits = ProtocolLimits::default();
+        let presence = |text: String| PresencePayload {
+            room: Uuid::nil(),
+            status: PresenceStatus::Away,
+            status_text: Some(text),
+        };
+        presence("brb".into()).validate(&limits).unwrap();
+        presence("a".repeat(PresencePayload::MAX_STATUS_TEXT_LEN))
+            .validate(&limits)
+            .unwrap();
+        let err = presence("a".repeat(PresencePayload::MAX_STATUS_TEXT_LEN + 1))
+            .validate(&limits)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "status text too long");
+
+        let err = presence("b\u{1b}[2Jrb\n".into())
+            .validate(&limits)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "control characters in status text");
+        let clean = PresencePayload::sanitize_status_text("b\u{1b}[2Jrb\n");
+        assert_eq!(clean, "b[2Jrb");
+        presence(clean).validate(&limits).unwrap();
+    }
+
+    #[test]
+    fn userkey_from_signing_key() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let user = UserKey::from_signing_key(&key);
+        assert_eq!(user.0, key.verifying_key().to_bytes());
+        assert_eq!(UserKey::from(&key), user);
+        let item = WithSig::sign(&key, &mut rand::rngs::OsRng, ()).unwrap();
+        assert_eq!(item.signee.user, user);
+    }
+
+    #[test]
+    fn userkey_write_hex() {
+        let mut raw = [0u8; PUBLIC_KEY_LENGTH];
+        raw.iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b = (i * 37) as u8);
+        let key = UserKey(raw);
+        let mut out = String::new();
+        key.write_hex(&mut out).unwrap();
+        assert_eq!(out, hex::encode(raw));
+        assert_eq!(key.to_string(), out);
+    }
+
+    #[test]
+    fn userkey_shard() {
+        let key = UserKey::from_signing_key(&SigningKey::from_bytes(&[1; 32]));
+        assert_eq!(key.shard(7), key.shard(7));
+        assert_eq!(UserKey([0xAB; 32]).shard(1000), 0xABAB % 1000);
+        assert_eq!(key.shard(1), 0);
+
+        const SHARDS: u16 = 8;
+        const N: u32 = 4000;
+        let mut counts = [0u32; SHARDS as usize];
+        for i in 0..N {
+            let key = UserKey(Sha256::digest(i.to_le_bytes()).into());
+            counts[usize::from(key.shard(SHARDS))] += 1;
+        }
+        let expect = N / u32::from(SHARDS);
+        for count in counts {
+            assert!(count.abs_diff(expect) < expect / 5, "{counts:?}");
+        }
+    }
+
+    #[test]
+    fn parse_userkey() {
+        let key = SigningKey::from_bytes(&[1; 32]).verifying_key();
+        let hex = hex::encode(key.to_bytes());
+        assert_eq!(hex.parse::<UserKey>().unwrap().0, key.to_bytes());
+        assert_eq!(
+            hex[..62].parse::<UserKey>(),
+            Err(UserKeyParseError::InvalidLength(62)),
+        );
+        assert_eq!(
+            format!("{hex}00").parse::<UserKey>(),
+            Err(UserKeyParseError::InvalidLength(66)),
+        );
+        assert_eq!(
+            format!("{}é", &hex[..63]).parse::<UserKey>(),
+            Err(UserKeyParseError::InvalidChar { ch: 'é', pos: 63 }),
+        );
+        // The y-coordinate 2 has no corresponding x on the curve.
+        let invalid = format!("02{}", "0".repeat(62));
+        assert_eq!(
+            invalid.parse::<UserKey>(),
+            Err(UserKeyParseError::InvalidPoint)
+        );
+    }
+
+    #[test]
+    fn set_title() {
+        let room = Uuid::nil();
+        let op = RoomAdminPayload::SetTitle {
+            room,
+            title: "new title".into(),
+        };
+        let json = serde_json::to_string(&op).unwrap();
+        assert_eq!(
+            json,
+            format!(r#"{{"typ":"set_title","room":"{room}","title":"new title"}}"#),
+        );
+        let op = serde_json::from_str::<RoomAdminPayload>(&json).unwrap();
+        op.validate(&ProtocolLimits::default()).unwrap();
+
+        for title in [String::new(), "x".repeat(MAX_TITLE_LEN + 1)] {
+            RoomAdminPayload::SetTitle { room, title }
+                .validate(&ProtocolLimits::default())
+                .unwrap_err();
+        }
+    }
+
+    #[test]
+    fn set_description() {
+        let room = Uuid::nil();
+        let op = |description: String| RoomAdminPayload::SetDescription { description, room };
+        let json = serde_json::to_string(&op("about".into())).unwrap();
+        assert_eq!(
+            json,
+            format!(r#"{{"typ":"set_description","description":"about","room":"{room}"}}"#),
+        );
+        let any = serde_json::from_str::<AnyPayload>(&json).unwrap();
+        assert!(matches!(
+            any,
+            AnyPayload::RoomAdmin(RoomAdminPayload::SetDescription { .. })
+        ));
+
+        let limits = ProtocolLimits::default();
+        for ok in [String::new(), "x".repeat(MAX_DESCRIPTION_LEN)] {
+            op(ok).validate(&limits).unwrap();
+        }
+        op("x".repeat(MAX_DESCRIPTION_LEN + 1))
+            .validate(&limits)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn update_room_attrs() {
+        let room = Uuid::nil();
+        let op = |attrs| RoomAdminPayload::UpdateRoomAttrs { attrs, room };
+        let json = serde_json::to_string(&op(RoomAttrs::PUBLIC_READABLE)).unwrap();
+        assert_eq!(
+            json,
+            format!(r#"{{"typ":"update_room_attrs","attrs":1,"room":"{room}"}}"#),
+        );
+        let any = serde_json::from_str::<AnyPayload>(&json).unwrap();
+        assert!(matches!(
+            any,
+            AnyPayload::RoomAdmin(RoomAdminPayload::UpdateRoomAttrs { .. })
+        ));
+
+        let limits = ProtocolLimits::default();
+        op(RoomAttrs::PUBLIC_READABLE).validate(&limits).unwrap();
+        op(RoomAttrs::from_bits_retain(1 << 63))
+            .validate(&limits)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn userkey_ord() {
+        let keys = [[0u8; 32], [1; 32], [0xFF; 32], {
+            let mut k = [0; 32];
+            k[31] = 1;
+            k
+        }];
+        for a in keys {
+            for b in keys {
+                assert_eq!(UserKey(a).cmp(&UserKey(b)), a.cmp(&b));
+            }
+        }
+    }
+
+    #[test]
+    fn write_canonical() {
+        let signee = Signee {
+            nonce: 42,
+            payload: ChatPayload {
+                room: Uuid::nil(),
+                sequence: None,
+                text: "x".repeat(1 << 16),
+            },
+            timestamp: 1724966284,
+            user: UserKey([1; 32]),
+            version: PROTOCOL_VERSION,
+        };
+        let mut buf = Vec::new();
+        signee.write_canonical(&mut buf).unwrap();
+        assert_eq!(buf, signee.canonical_bytes().unwrap());
+    }
+
+    #[test]
+    fn reject_malleable_signature() {
+        // The order of the Ed25519 base point in little endian.
+        const L: [u8; 32] = [
+            0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9,
+            0xde, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x10,
+        ];
+
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let payload = ChatPayload {
+            room: Uuid::nil(),
+            sequence: None,
+            text: "hello".into(),
+        };
+        let mut item = ChatItem::sign(&key, &mut rand::rngs::OsRng, payload).unwrap();
+        item.verify().unwrap();
+
+        // S' = S + L is congruent to S modulo L, passing a non-strict check.
+        let mut carry = 0u16;
+        for (s, l) in item.sig[32..].iter_mut().zip(L) {
+            let sum = *s as u16 + l as u16 + carry;
+            *s = sum as u8;
+            carry = sum >> 8;
+        }
+        assert_eq!(carry, 0);
+        item.verify().unwrap_err();
+    }
+
+    #[test]
+    fn with_sig_from_value() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let payload = ChatPayload {
+            room: Uuid::nil(),
+            sequence: None,
+            text: "hello".into(),
+        };
+        let item = ChatItem::sign(&key, &mut rand::rngs::OsRng, payload).unwrap();
+        let mut v = serde_json::to_value(&item).unwrap();
+        let item = ChatItem::try_from_value(v.clone()).unwrap();
+        item.verify().unwrap();
+
+        v["signee"]["extra"] = 42.into();
+        ChatItem::try_from_value(v).unwrap_err();
+    }
+
+    #[test]
+    fn chat_sequence_serde() {
+        let mut chat = ChatPayload {
+            room: Uuid::nil(),
+            sequence: None,
+            text: "hi".into(),
+        };
+        let legacy = format!(r#"{{"typ":"chat","room":"{}","text":"hi"}}"#, Uuid::nil());
+        assert_eq!(serde_json::to_string(&chat).unwrap(), legacy);
+        assert_eq!(
+            serde_json::from_str::<ChatPayload>(&legacy)
+                .unwrap()
+                .sequence,
+            None
+        );
+
+        chat.sequence = Some(3);
+        let json = serde_json::to_string(&chat).unwrap();
+        assert_eq!(
+            json,
+            format!(
+                r#"{{"typ":"chat","room":"{}","sequence":3,"text":"hi"}}"#,
+                Uuid::nil()
+            ),
+        );
+        assert_eq!(
+            serde_json::from_str::<ChatPayload>(&json).unwrap().sequence,
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn custom_limits() {
+        let limits = ProtocolLimits {
+            max_text_len: 4,
+            ..ProtocolLimits::default()
+        };
+        let chat = |text: &str| ChatPayload {
+            room: Uuid::nil(),
+            sequence: None,
+            text: text.into(),
+        };
+        chat("1234").validate(&limits).unwrap();
+        chat("12345").validate(&limits).unwrap_err();
+        chat("12345").validate(&ProtocolLimits::default()).unwrap();
+
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let item = ChatItem::sign(&key, &mut rand::rngs::OsRng, chat("hello")).unwrap();
+        let bytes = serde_json::to_vec(&item).unwrap();
+        ChatItem::ingest(&bytes, &ProtocolLimits::default()).unwrap();
+        let limits = ProtocolLimits {
+            max_frame_len: bytes.len() - 1,
+            ..ProtocolLimits::default()
+        };
+        ChatItem::ingest(&bytes, &limits).unwrap_err();
+    }
+
+    #[test]
+    fn json_depth_limit() {
+        let nested = |depth: usize| "[".repeat(depth) + &"]".repeat(depth);
+        check_json_depth(nested(3).as_bytes(), 3).unwrap();
+        check_json_depth(nested(4).as_bytes(), 3).unwrap_err();
+        // Brackets in strings are not counted.
+        check_json_depth(br#"[{"a":"[[[\"{{"}]"#, 2).unwrap();
+
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let item = |depth| {
+            let payload = serde_json::from_str::<AnyPayload>(&format!(
+                r#"{{"typ":"unknown","value":{}}}"#,
+                nested(depth),
+            ))
+            .unwrap();
+            let item = WithSig::sign(&key, &mut rand::rngs::OsRng, payload).unwrap();
+            serde_json::to_vec(&item).unwrap()
+        };
+        let limits = ProtocolLimits::default();
+        // The envelope, signee and payload take 3 levels.
+        WithSig::<AnyPayload>::ingest(&item(limits.max_json_depth - 3), &limits).unwrap();
+        let err =
+            WithSig::<AnyPayload>::ingest(&item(limits.max_json_depth - 2), &limits).unwrap_err();
+        assert_eq!(err.to_string(), "JSON nested too deep");
+    }
+
+    #[test]
+    fn verify_opaque_any_payload() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let opts = VerifyOptions::default();
+        let chat = ChatItem::sign(
+            &key,
+            &mut rand::rngs::OsRng,
+            ChatPayload {
+                room: Uuid::nil(),
+                sequence: Some(1),
+                text: "hello".into(),
+            },
+        )
+        .unwrap();
+        let unknown = serde_json::from_str::<AnyPayload>(
+            r#"{"typ":"future","b":{"y":1,"x":[true]},"a":"z"}"#,
+        )
+        .unwrap();
+        let unknown = WithSig::sign(&key, &mut rand::rngs::OsRng, unknown).unwrap();
+        for bytes in [
+            serde_json::to_vec(&chat).unwrap(),
+            serde_json::to_vec(&unknown).unwrap(),
+        ] {
+            assert_eq!(
+                verify_opaque(&bytes, &opts).unwrap(),
+                UserKey::from_signing_key(&key)
+            );
+        }
+
+        let json = serde_json::to_string(&chat).unwrap();
+        verify_opaque(json.replace("hello", "hellO").as_bytes(), &opts).unwrap_err();
+        let extra = json.replacen('{', r#"{"extra":1,"#, 1);
+        let err = verify_opaque(extra.as_bytes(), &opts).unwrap_err();
+        assert_eq!(err.to_string(), "invalid signed payload");
+    }
+
+    #[test]
+    fn ingest_empty_chat() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let ingest = |text: &str| {
+            let chat = ChatPayload {
+                room: Uuid::nil(),
+                sequence: None,
+                text: text.into(),
+            };
+            let item = ChatItem::sign(&key, &mut rand::rngs::OsRng, chat).unwrap();
+            ChatItem::ingest(
+                &serde_json::to_vec(&item).unwrap(),
+                &ProtocolLimits::default(),
+            )
+        };
+        ingest("hello").unwrap();
+        let err = ingest("").unwrap_err();
+        assert_eq!(err.to_string(), "empty text");
+    }
+
+    #[test]
+    fn non_integer_numbers() {
+        let chat = ChatPayload {
+            room: Uuid::nil(),
+            sequence: None,
+            text: "hello".into(),
+        };
+        let item = ChatItem::sign(
+            &SigningKey::from_bytes(&[1; 32]),
+            &mut rand::rngs::OsRng,
+            chat,
+        )
+        .unwrap();
+        let json = serde_json::to_string(&item).unwrap();
+        serde_json::from_str::<ChatItem>(&json).unwrap();
+
+        // Integer fields must have exactly one JSON representation, or the same logical item
+        // would have multiple canonical forms.
+        let ts = format!(r#""timestamp":{}"#, item.signee.timestamp);
+        let nonce = format!(r#""nonce":{}"#, item.signee.nonce);
+        for (orig, alt) in [
+            (&ts, format!(r#""timestamp":{}.0"#, item.signee.timestamp)),
+            (&ts, format!(r#""timestamp":"{}""#, item.signee.timestamp)),
+            (&ts, format!(r#""timestamp":{}e0"#, item.signee.timestamp)),
+            (&ts, r#""timestamp":-1"#.into()),
+            (&ts, format!("{ts},{ts}")),
+            (&nonce, format!(r#""nonce":{}.0"#, item.signee.nonce)),
+            (&nonce, format!(r#""nonce":"{}""#, item.signee.nonce)),
+            (&nonce, format!("{nonce},{nonce}")),
+        ] {
+            let tampered = json.replacen(orig.as_str(), &alt, 1);
+            assert_ne!(tampered, json);
+            serde_json::from_str::<ChatItem>(&tampered).unwrap_err();
+        }
+    }
+
+    #[cfg(feature = "sql-varint")]
+    #[test]
+    fn varint_flags_sql() {
+        use rusqlite::types::{FromSql, ToSqlOutput, Value, ValueRef};
+        use rusqlite::ToSql;
+
+        fn encode<F: Flags<Bits = u64>>(flags: F) -> Vec<u8> {
+            match VarintFlags(flags).to_sql().unwrap() {
+                ToSqlOutput::Owned(Value::Blob(b)) => b,
+                v => panic!("unexpected output {v:?}"),
+            }
+        }
+        fn decode<F: Flags<Bits = u64>>(buf: &[u8]) -> rusqlite::types::FromSqlResult<F> {
+            VarintFlags::<F>::column_result(ValueRef::Blob(buf)).map(|v| v.0)
+        }
+
+        for perm in [
+            MemberPermission::empty(),
+            MemberPermission::POST_CHAT,
+            MemberPermission::POST_CHAT | MemberPermission::UPDATE_ROOM,
+            MemberPermission::ALL,
+            MemberPermission::from_bits_retain(1 << 63),
+        ] {
+            let buf = encode(perm);
+            assert_eq!(decode::<MemberPermission>(&buf).unwrap(), perm);
+        }
+        assert_eq!(encode(MemberPermission::empty()), [0]);
+        assert_eq!(encode(MemberPermission::POST_CHAT).len(), 1);
+        let all = encode(ServerPermission::ALL);
+        assert_eq!(all.len(), 10);
+        assert_eq!(
+            decode::<ServerPermission>(&all).unwrap(),
+            ServerPermission::ALL
+        );
+
+        // Empty, unterminated, non-minimal and overflowing encodings.
+        for buf in [
+            &[][..],
+            &[0x80],
+            &[0x81, 0x00],
+            &[0xFF; 10],
+            &[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x02],
+        ] {
+            decode::<MemberPermission>(buf).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn create_room_members() {
+        let payload = CreateRoomPayload {
+            attrs: RoomAttrs::empty(),
+            members: RoomMemberList(vec![RoomMember {
+                permission: MemberPermission::ALL,
+                user: UserKey([1; 32]),
+            }]),
+            title: "title".into(),
+        };
+        assert_eq!(payload.member_count(), 1);
+        assert!(payload.is_member(&UserKey([1; 32])));
+        assert!(!payload.is_member(&UserKey([2; 32])));
+    }
+
+    #[test]
+    fn reassemble_chunks() {
+        let group = Uuid::new_v4();
+        let chunk = |index: u16, text: &str| ChatChunk {
+            group,
+            index,
+            room: Uuid::nil(),
+            text: text.into(),
+            total: 3,
+        };
+        let chunks = [chunk(2, "c"), chunk(0, "a"), chunk(1, "b")];
+        assert_eq!(ChatChunk::reassemble(&chunks).unwrap(), "abc");
+
+        let err = ChatChunk::reassemble(&[chunk(0, "a"), chunk(2, "c")]).unwrap_err();
+        assert_eq!(err.to_string(), "missing chunk 1");
+        let err = ChatChunk::reassemble(&[chunk(0, "a"), chunk(1, "b")]).unwrap_err();
+        assert_eq!(err.to_string(), "expecting 3 chunks, got 2");
+        ChatChunk::reassemble(&[chunk(0, "a"), chunk(0, "a"), chunk(1, "b")]).unwrap_err();
+        ChatChunk::reassemble(&[chunk(0, "a"), chunk(1, "b"), chunk(2, "c"), chunk(3, "d")])
+            .unwrap_err();
+    }
+
+    #[test]
+    fn server_event_serde() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let room = Uuid::nil();
+        let chat = ChatItem::sign(
+            &key,
+            &mut rand::rngs::OsRng,
+            ChatPayload {
+                room,
+                sequence: None,
+                text: "hi".into(),
+            },
+        )
+        .unwrap();
+        let target = MessageId::of(&chat);
+        let presence = PresenceItem::sign(
+            &key,
+            &mut rand::rngs::OsRng,
+            PresencePayload {
+                room,
+                status: PresenceStatus::Away,
+                status_text: None,
+            },
+        )
+        .unwrap();
+        let events = [
+            (ServerEvent::NewChat(chat), "new_chat"),
+            (
+                ServerEvent::MemberChanged {
+                    diff: MemberDiff {
+                        added: vec![RoomMember {
+                            permission: MemberPermission::POST_CHAT,
+                            user: UserKey([2; 32]),
+                        }],
+                        removed: vec![UserKey([3; 32])],
+                    },
+                    room,
+                },
+                "member_changed",
+            ),
+            (ServerEvent::Presence(presence), "presence"),
+            (ServerEvent::Tombstone { room, target }, "tombstone"),
+        ];
+        for (event, typ) in events {
+            let v = serde_json::to_value(&event).unwrap();
+            assert_eq!(v["typ"], typ);
+            let back = serde_json::from_value::<ServerEvent>(v.clone()).unwrap();
+            assert_eq!(serde_json::to_value(&back).unwrap(), v);
+        }
+    }
+
+    #[test]
+    fn userkey_to_montgomery() {
+        // Public key of RFC8032 section 7.1 test 1.
+        let key = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a"
+            .parse::<UserKey>()
+            .unwrap();
+        assert_eq!(
+            hex::encode(key.to_montgomery().unwrap()),
+            "d85e07ec22b0ad881537c2f44d662d1a143cf830c57aca4305d85c7a90f6b62e",
+        );
+
+        let mut invalid = [0u8; 32];
+        invalid[0] = 2;
+        UserKey(invalid).to_montgomery().unwrap_err();
+    }
+
+    #[cfg(feature = "sealed-sender")]
+    #[test]
+    fn sealed_sender() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let chat = ChatPayload {
+            room: Uuid::nil(),
+            sequence: None,
+            text: "psst".into(),
+        };
+        let item = ChatItem::sign(&key, &mut rand::rngs::OsRng, chat).unwrap();
+        let user = item.signee.user.clone();
+        let sig = hex::encode(item.sig);
+        let room_key = [42u8; 32];
+        let sealed = SealedSig::seal(item, &room_key, &mut rand::rngs::OsRng);
+        let json = serde_json::to_string(&sealed).unwrap();
+        assert!(!json.contains(&user.to_string()));
+
+        // A holder of every member key but not the room key has no signature to check against
+        // the candidate authors.
+        assert!(!json.contains(&sig));
+        let other = SigningKey::from_bytes(&[2; 32]);
+        let members = [UserKey(other.verifying_key().to_bytes()), user.clone()];
+        let candidates = sealed
+            .sealed
+            .chunks(SIGNATURE_LENGTH)
+            .filter_map(|c| c.try_into().ok());
+        for (sig, member) in candidates.flat_map(|sig| members.iter().map(move |m| (sig, m))) {
+            let guess = WithSig {
+                sig,
+                signee: Signee {
+                    nonce: sealed.signee.nonce,
+                    payload: sealed.signee.payload.clone(),
+                    timestamp: sealed.signee.timestamp,
+                    user: member.clone(),
+                    version: sealed.signee.version,
+                },
+            };
+            guess.verify_signature().unwrap_err();
+        }
+
+        let sealed = serde_json::from_str::<SealedSig<ChatPayload>>(&json).unwrap();
+        sealed.unseal(&[0u8; 32]).unwrap_err();
+
+        let sealed = serde_json::from_str::<SealedSig<ChatPayload>>(&json).unwrap();
+        let item = sealed.unseal(&room_key).unwrap();
+        assert_eq!(item.signee.user, user);
+        item.verify().unwrap();
+    }
+
+    #[test]
+    fn room_attrs_validate_known() {
+        RoomAttrs::empty().validate_known().unwrap();
+        RoomAttrs::PUBLIC_READABLE.validate_known().unwrap();
+        let unknown = RoomAttrs::from_bits_retain(1 << 63) | RoomAttrs::PUBLIC_READABLE;
+        assert_eq!(unknown.unknown_bits().bits(), 1 << 63);
+        unknown.validate_known().unwrap_err();
+    }
+
+    #[test]
+    fn room_attrs_tags() {
+        assert!(RoomAttrs::empty().to_tags().is_empty());
+        let attrs = RoomAttrs::PUBLIC_READABLE;
+        let tags = attrs.to_tags();
+        assert_eq!(tags, ["public_readable"]);
+        let tags = tags.iter().map(String::as_str).collect::<Vec<_>>();
+        assert_eq!(RoomAttrs::from_tags(&tags).unwrap(), attrs);
+        assert_eq!(RoomAttrs::from_tags(&[]).unwrap(), RoomAttrs::empty());
+
+        let unknown = RoomAttrs::from_bits_retain(1 << 63) | RoomAttrs::PUBLIC_READABLE;
+        assert_eq!(unknown.to_tags(), ["public_readable"]);
+        for bad in ["PUBLIC_READABLE", "private", ""] {
+            RoomAttrs::from_tags(&["public_readable", bad]).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn timestamp_format() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(1_725_194_096), "2024-09-01T12:34:56Z");
+        assert_eq!(format_timestamp(u64::MAX), "9999-12-31T23:59:59Z");
+        for ts in [0, 1_725_194_096, MAX_RFC3339_TIMESTAMP] {
+            assert_eq!(parse_timestamp(&format_timestamp(ts)).unwrap(), ts);
+        }
+        assert_eq!(
+            parse_timestamp("2024-09-01T12:34:56.789Z").unwrap(),
+            1_725_194_096
+        );
+        for bad in [
+            "",
+            "1725194096",
+            "2024-09-01 12:34:56",
+            "2024-13-01T00:00:00Z",
+        ] {
+            parse_timestamp(bad).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn fresh_timestamp() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let chat = ChatPayload {
+            room: Uuid::nil(),
+            sequence: None,
+            text: "draft".into(),
+        };
+        let payload = serde_json::to_vec(&chat).unwrap();
+        let signee = Signee {
+            nonce: 42,
+            payload: chat,
+            timestamp: 100,
+            user: UserKey(key.verifying_key().to_bytes()),
+            version: PROTOCOL_VERSION,
+        };
+        let signee = signee.with_fresh_timestamp(200);
+        assert_eq!((signee.nonce, signee.timestamp), (42, 200));
+        assert_eq!(serde_json::to_vec(&signee.payload).unwrap(), payload);
+
+        let draft = ChatItem::sign_signee(&key, signee).unwrap();
+        draft.verify().unwrap_err();
+        let item = draft.refresh(&key, &mut rand::rngs::OsRng).unwrap();
+        assert!(item.signee.timestamp > 200);
+        assert_eq!(serde_json::to_vec(&item.signee.payload).unwrap(), payload);
+        item.verify().unwrap();
+
+        let other = SigningKey::from_bytes(&[2; 32]);
+        item.refresh(&other, &mut rand::rngs::OsRng).unwrap_err();
+    }
+
+    #[test]
+    fn detached_signature() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let user = UserKey(key.verifying_key().to_bytes());
+        let msg = b"\x00attachment\xFF";
+        let sig = sign_detached(&key, msg);
+        verify_detached(&user, msg, &sig).unwrap();
+        verify_detached(&user, b"\x00attachment\xFE", &sig).unwrap_err();
+        verify_detached(&UserKey([2; 32]), msg, &sig).unwrap_err();
+    }
+
+    #[test]
+    fn join_request_serde() {
+        let room = Uuid::new_v4();
+        let json = serde_json::to_string(&JoinRequestPayload { room }).unwrap();
+        assert_eq!(json, format!(r#"{{"typ":"join_request","room":"{room}"}}"#));
+        let back = serde_json::from_str::<JoinRequestPayload>(&json).unwrap();
+        assert_eq!(back.room, room);
+    }
+
+    #[test]
+    fn leave_serde() {
+        let room = Uuid::new_v4();
+        let json = serde_json::to_string(&LeavePayload { room }).unwrap();
+        assert_eq!(json, format!(r#"{{"typ":"leave","room":"{room}"}}"#));
+        let back = serde_json::from_str::<LeavePayload>(&json).unwrap();
+        assert_eq!(back.room, room);
+    }
+
+    #[test]
+    fn search_tokens() {
+        let key = SigningKey::from_bytes(&[1; 32]).verifying_key();
+        let chat = |text: String| ChatPayload {
+            room: Uuid::nil(),
+            sequence: None,
+            text,
+        };
+
+        let tokens =
+            chat("Hello, World! Grüße aus MÜNCHEN. 你好世界 안녕 세계".into()).search_tokens();
+        assert_eq!(
+            tokens,
+            [
+                "hello", "world", "grüße", "aus", "münchen", "你", "好", "世", "界", "안녕",
+                "세계",
+            ],
+        );
+
+        let key = hex::encode(key.to_bytes());
+        let tokens = chat(format!("@{key} ping,@{key}: pong @{key}x mail@example")).search_tokens();
+        assert_eq!(tokens[..2], ["ping", "pong"]);
+        // Not a mention: trailing alphanumerics, or too short.
+        assert_eq!(
+            tokens[2..],
+            [format!("{key}x"), "mail".into(), "example".into()]
+        );
+    }
+
+    #[test]
+    fn read_capability() {
+        let room = Uuid::new_v4();
+        let cap = ReadCapability::generate(room, &mut rand::rngs::OsRng);
+        let frag = cap.to_url_fragment();
+        assert_eq!(frag.len(), 36 + 1 + 64);
+        for s in [frag.clone(), format!("#{frag}")] {
+            let back = ReadCapability::from_url_fragment(&s).unwrap();
+            assert_eq!(back.room, room);
+            assert!(back.validate(&cap.secret));
+        }
+
+        // Tampered secret.
+        let mut tampered = cap.clone();
+        tampered.secret[31] ^= 1;
+        assert!(!tampered.validate(&cap.secret));
+        let back = ReadCapability::from_url_fragment(&tampered.to_url_fragment()).unwrap();
+        assert!(!back.validate(&cap.secret));
+
+        for bad in [
+            &frag[..frag.len() - 1],
+            &frag.replace('.', ""),
+            &frag[1..],
+            "",
+        ] {
+            ReadCapability::from_url_fragment(bad).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn hex_opt() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Opt {
+            #[serde(with = "hex_opt")]
+            array: Option<[u8; 4]>,
+            #[serde(default, skip_serializing_if = "Option::is_none", with = "hex_opt")]
+            bytes: Option<Vec<u8>>,
+        }
+
+        let v = Opt {
+            array: Some([0xde, 0xad, 0xbe, 0xef]),
+            bytes: Some(vec![1, 2, 3]),
+        };
+        let json = serde_json::to_string(&v).unwrap();
+        assert_eq!(json, r#"{"array":"deadbeef","bytes":"010203"}"#);
+        assert_eq!(serde_json::from_str::<Opt>(&json).unwrap(), v);
+
+        let v = Opt {
+            array: None,
+            bytes: None,
+        };
+        let json = serde_json::to_string(&v).unwrap();
+        assert_eq!(json, r#"{"array":null}"#);
+        assert_eq!(serde_json::from_str::<Opt>(&json).unwrap(), v);
+
+        let err = serde_json::from_str::<Opt>(r#"{"array":"dead"}"#).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("invalid length: expected 4 bytes (8 hex digits), got 4 hex digits"),
+            "{err}",
+        );
+        serde_json::from_str::<Opt>(r#"{"array":null,"bytes":"0"}"#).unwrap_err();
+    }
+
+    #[test]
+    fn hex_length_error() {
+        for len in [63, 65] {
+            let json = format!(r#""{}""#, "1".repeat(len));
+            let err = serde_json::from_str::<UserKey>(&json).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("invalid length: expected 32 bytes (64 hex digits), got {len} hex digits"),
+            );
+        }
+
+        let item = WithSig::sign(
+            &SigningKey::from_bytes(&[1; 32]),
+            &mut rand::rngs::OsRng,
+            serde_json::json!(null),
+        )
+        .unwrap();
+        let mut v = serde_json::to_value(&item).unwrap();
+        for sig in ["00".repeat(63), "00".repeat(65)] {
+            v["sig"] = serde_json::json!(sig);
+            let err = WithSig::<serde_json::Value>::try_from_value(v.clone()).unwrap_err();
+            assert!(
+                format!("{err:#}").contains(&format!(
+                    "expected 64 bytes (128 hex digits), got {} hex digits",
+                    sig.len()
+                )),
+                "{err:#}",
+            );
+        }
+    }
+
+    #[test]
+    fn verify_offline() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let sign_at = |timestamp: u64| {
+            let signee = Signee {
+                nonce: 42,
+                payload: serde_json::Value::Null,
+                timestamp,
+                user: UserKey(key.verifying_key().to_bytes()),
+                version: PROTOCOL_VERSION,
+            };
+            WithSig::sign_signee(&key, signee).unwrap()
+        };
+        let opts = |timestamp| VerifyOptions {
+            limits: ProtocolLimits::default(),
+            skip_timestamp: false,
+            timestamp,
+        };
+        let offline = opts(TimestampPolicy::Offline { max_age: 7200 });
+
+        let now = get_timestamp();
+        let hour_ago = sign_at(now - 3600);
+        hour_ago.verify().unwrap_err();
+        hour_ago
+            .verify_with_options(&opts(TimestampPolicy::Fresh))
+            .unwrap_err();
+        hour_ago.verify_with_options(&offline).unwrap();
+        hour_ago
+            .verify_with_options(&opts(TimestampPolicy::Offline { max_age: 600 }))
+            .unwrap_err();
+        sign_at(now + 3600)
+            .verify_with_options(&offline)
+            .unwrap_err();
+
+        let ancient = sign_at(1);
+        ancient.verify_with_options(&offline).unwrap_err();
+        ancient
+            .verify_with_options(&opts(TimestampPolicy::Informational))
+            .unwrap();
+        let mut forged = ancient;
+        forged.signee.timestamp = 2;
+        forged
+            .verify_with_options(&opts(TimestampPolicy::Informational))
+            .unwrap_err();
+    }
+
+    #[test]
+    fn wire_format_negotiation() {
+        use WireFormat::{Binary, Json};
+
+        let all = [Json, Binary];
+        assert_eq!(choose_format(&[], &all), Json);
+        assert_eq!(choose_format(&[Json], &all), Json);
+        assert_eq!(choose_format(&[Binary], &all), Binary);
+        assert_eq!(choose_format(&[Json, Binary], &all), Binary);
+        assert_eq!(choose_format(&[Binary, Json], &all), Binary);
+        // Never choose what the server does not support.
+        assert_eq!(choose_format(&[Binary], &[Json]), Json);
+        assert_eq!(choose_format(&[Json, Binary], &[]), Json);
+        assert_eq!(
+            serde_json::from_str::<Vec<WireFormat>>(r#"["json","binary"]"#).unwrap(),
+            [Json, Binary],
+        );
+    }
+
+    #[test]
+    fn expired_by_secs() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let opts = VerifyOptions::default();
+        let tolerance = opts.limits.timestamp_tolerance;
+        let sign = |time| WithSig::sign_at(&key, &mut rand::rngs::OsRng, (), time).unwrap();
+        let expired_by = |item: &WithSig<()>, opts: &VerifyOptions| match item
+            .verify_with_options(opts)
+            .unwrap_err()
+        {
+            VerifyError::ExpiredTimestamp { expired_by_secs } => expired_by_secs,
+            err => panic!("{err:?}"),
+        };
+        // Allow the clock to tick a second between signing and verification.
+        let old = sign(SystemTime::now() - Duration::from_secs(120));
+        let got = expired_by(&old, &opts);
+        assert!([120 - tolerance, 121 - tolerance].contains(&got), "{got}");
+        let future = sign(SystemTime::now() + Duration::from_secs(120));
+        let got = expired_by(&future, &opts);
+        assert!([119 - tolerance, 120 - tolerance].contains(&got), "{got}");
+
+        let offline = VerifyOptions {
+            timestamp: TimestampPolicy::Offline { max_age: 100 },
+            ..VerifyOptions::default()
+        };
+        let got = expired_by(&old, &offline);
+        assert!([20, 21].contains(&got), "{got}");
+        assert_eq!(
+            VerifyError::ExpiredTimestamp {
+                expired_by_secs: 30
+            }
+            .to_string(),
+            "invalid timestamp, expired by 30s",
+        );
+    }
+
+    #[test]
+    fn verify_skip_timestamp() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let old = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1);
+        let mut item = WithSig::sign_at(&key, &mut rand::rngs::OsRng, (), old).unwrap();
+        let trusted = VerifyOptions {
+            skip_timestamp: true,
+            ..VerifyOptions::default()
+        };
+
+        let err = item
+            .verify_with_options(&VerifyOptions::default())
+            .unwrap_err();
+        assert!(
+            matches!(err, VerifyError::ExpiredTimestamp { .. }),
+            "{err:?}"
+        );
+        item.verify_with_options(&trusted).unwrap();
+
+        // The signature is still checked.
+        item.signee.nonce ^= 1;
+        let err = item.verify_with_options(&trusted).unwrap_err();
+        assert!(matches!(err, VerifyError::InvalidSignature), "{err:?}");
+    }
+
+    #[test]
+    fn member_list_canonical_hash() {
+        let member = |b: u8, permission| RoomMember {
+            permission,
+            user: UserKey([b; 32]),
+        };
+        let list = |mut members: Vec<RoomMember>| {
+            members.sort_by(|a, b| a.user.cmp(&b.user));
+            RoomMemberList::try_from(members).unwrap()
+        };
+        let perm = MemberPermission::POST_CHAT;
+        let a = list(vec![member(1, perm), member(2, perm), member(3, perm)]);
+        let b = list(vec![member(3, perm), member(1, perm), member(2, perm)]);
+        assert_eq!(a.canonical_hash(), b.canonical_hash());
+        let c = list(vec![
+            member(3, perm),
+            member(1, perm),
+            member(2, MemberPermission::ALL),
+        ]);
+        assert_ne!(a.canonical_hash(), c.canonical_hash());
+    }
+
+    #[test]
+    fn member_list_error() {
+        let member = |b: u8| RoomMember {
+            permission: MemberPermission::ALL,
+            user: UserKey([b; 32]),
+        };
+        let try_list = |members| RoomMemberList::try_from(members).map(|_| ());
+        assert_eq!(try_list(vec![member(1), member(2)]), Ok(()));
+        assert_eq!(try_list(vec![]), Err(MemberListError::Empty));
+        assert_eq!(
+            try_list(vec![member(2), member(1)]),
+            Err(MemberListError::Unsorted)
+        );
+        assert_eq!(
+            try_list(vec![member(1), member(2), member(2)]),
+            Err(MemberListError::Duplicate(UserKey([2; 32])))
+        );
+
+        let payload = CreateRoomPayload {
+            attrs: RoomAttrs::empty(),
+            members: RoomMemberList(vec![member(1)]),
+            title: "test".into(),
+        };
+        assert_eq!(payload.check_creator(&UserKey([1; 32])), Ok(()));
+        assert_eq!(
+            payload.check_creator(&UserKey([2; 32])),
+            Err(MemberListError::CreatorMissing)
+        );
+    }
+
+    #[test]
+    fn member_list_from_unsorted() {
+        let member = |b: u8| RoomMember {
+            permission: MemberPermission::POST_CHAT,
+            user: UserKey([b; 32]),
+        };
+        let list = RoomMemberList::from_unsorted(vec![member(3), member(1), member(2)]).unwrap();
+        let keys = list.0.iter().map(|m| m.user.0[0]).collect::<Vec<_>>();
+        assert_eq!(keys, [1, 2, 3]);
+
+        let err = RoomMemberList::from_unsorted(vec![member(2), member(1), member(2)]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MemberListError>(),
+            Some(&MemberListError::Duplicate(UserKey([2; 32])))
+        );
+    }
+
+    #[test]
+    fn create_room_canonical_members() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let member = |b: u8| RoomMember {
+            permission: MemberPermission::POST_CHAT,
+            user: UserKey([b; 32]),
+        };
+        let sign = |members| {
+            let payload = CreateRoomPayload {
+                attrs: RoomAttrs::empty(),
+                members: RoomMemberList(members),
+                title: "title".into(),
+            };
+            let mut rng = rand::rngs::mock::StepRng::new(42, 0);
+            WithSig::sign_at(&key, &mut rng, payload, SystemTime::UNIX_EPOCH).unwrap()
+        };
+        let sorted = sign(vec![member(1), member(2), member(3)]);
+        let unsorted = sign(vec![member(3), member(1), member(2)]);
+        assert_eq!(
+            sorted.signee.canonical_bytes().unwrap(),
+            unsorted.signee.canonical_bytes().unwrap(),
+        );
+        assert_eq!(sorted.sig, unsorted.sig);
+        // Still parsed back strictly.
+        let json = serde_json::to_string(&unsorted).unwrap();
+        serde_json::from_str::<WithSig<CreateRoomPayload>>(&json)
+            .unwrap()
+            .verify_signature()
+            .unwrap();
+    }
+
+    #[test]
+    fn member_list_tsv() {
+        let key = |b: u8| UserKey(SigningKey::from_bytes(&[b; 32]).verifying_key().to_bytes());
+        let list = RoomMemberList::from_unsorted(vec![
+            RoomMember {
+                permission: MemberPermission::OWNER,
+                user: key(1),
+            },
+            RoomMember {
+                permission: MemberPermission::POST_CHAT | MemberPermission::ADD_MEMBER,
+                user: key(2),
+            },
+        ])
+        .unwrap();
+        let tsv = list.to_tsv();
+        let lines = tsv.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        for (line, m) in lines.iter().zip(&list.0) {
+            let perm = if m.permission.is_owner() {
+                "ALL"
+            } else {
+                "POST_CHAT|ADD_MEMBER"
+            };
+            assert_eq!(*line, format!("{}\t{perm}", m.user));
+        }
+        assert_eq!(
+            RoomMemberList::from_tsv(&tsv).unwrap().canonical_hash(),
+            list.canonical_hash(),
+        );
+
+        let swapped = lines.iter().rev().copied().collect::<Vec<_>>().join("\n");
+        let err = RoomMemberList::from_tsv(&swapped).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MemberListError>(),
+            Some(&MemberListError::Unsorted)
+        );
+        let err = RoomMemberList::from_tsv("01\tALL").unwrap_err();
+        assert_eq!(err.to_string(), "invalid line #1");
+        RoomMemberList::from_tsv(&format!("{}\tNOPE", key(1))).unwrap_err();
+    }
+
+    #[test]
+    fn room_key_payload() {
+        let recipient = |b: u8| RoomKeyRecipient {
+            sealed_key: vec![b; 4],
+            user: UserKey([b; 32]),
+        };
+        let room = Uuid::nil();
+        let payload = RoomKeyPayload {
+            recipients: vec![recipient(1), recipient(2)],
+            room,
+        };
+        let json = serde_json::to_string(&payload).unwrap();
+        let (k1, k2) = (UserKey([1; 32]), UserKey([2; 32]));
+        assert_eq!(
+            json,
+            format!(
+                r#"{{"typ":"room_key","recipients":[{{"sealed_key":"01010101","user":"{k1}"}},{{"sealed_key":"02020202","user":"{k2}"}}],"room":"{room}"}}"#
+            ),
+        );
+        let back = serde_json::from_str::<RoomKeyPayload>(&json).unwrap();
+        assert_eq!(back.recipients[1].sealed_key, [2; 4]);
+
+        let limits = ProtocolLimits::default();
+        payload.validate(&limits).unwrap();
+        for recipients in [
+            vec![],
+            vec![recipient(2), recipient(1)],
+            vec![recipient(1), recipient(1)],
+        ] {
+            RoomKeyPayload { recipients, room }
+                .validate(&limits)
+                .unwrap_err();
+        }
+    }
+
+    #[test]
+    fn flags_display() {
+        fn check<F>(flags: F, s: &str)
+        where
+            F: FromStr<Err = bitflags::parser::ParseError> + fmt::Display + fmt::Debug + PartialEq,
+        {
+            assert_eq!(flags.to_string(), s);
+            assert_eq!(s.parse::<F>().unwrap(), flags);
+        }
+
+        check(ServerPermission::CREATE_ROOM, "CREATE_ROOM");
+        check(ServerPermission::ALL, "ALL");
+        check(ServerPermission::empty(), "0x0");
+
+        check(MemberPermission::POST_CHAT, "POST_CHAT");
+        check(
+            MemberPermission::POST_CHAT | MemberPermission::ADD_MEMBER,
+            "POST_CHAT|ADD_MEMBER",
+        );
+        check(MemberPermission::ALL, "ALL");
+        check(
+            MemberPermission::UPDATE_ROOM | MemberPermission::from_bits_retain(1 << 8),
+            "UPDATE_ROOM|0x100",
+        );
+
+        check(RoomAttrs::PUBLIC_READABLE, "PUBLIC_READABLE");
+        check(RoomAttrs::from_bits_retain(0b110), "0x6");
+        check(RoomAttrs::all(), "PUBLIC_READABLE|0xfffffffffffffffe");
+
+        assert_eq!(
+            " POST_CHAT | UPDATE_ROOM "
+                .parse::<MemberPermission>()
+                .unwrap(),
+            MemberPermission::POST_CHAT | MemberPermission::UPDATE_ROOM,
+        );
+        for s in ["", "POST_CHAT|", "post_chat", "0xzz"] {
+            s.parse::<MemberPermission>().unwrap_err();
+        }
+    }
+
+    #[test]
+    fn any_payload() {
+        #[derive(Serialize)]
+        #[serde(tag = "typ", rename = "from_future")]
+        struct FuturePayload {
+            nested: serde_json::Value,
+            room: Uuid,
+        }
+
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let future = FuturePayload {
+            nested: serde_json::json!({ "b": [1, 2], "a": null }),
+            room: Uuid::nil(),
+        };
+        let item = WithSig::sign(&key, &mut rand::rngs::OsRng, future).unwrap();
+        let json = serde_json::to_string(&item).unwrap();
+        let any = serde_json::from_str::<WithSig<AnyPayload>>(&json).unwrap();
+        let AnyPayload::Unknown(unknown) = &any.signee.payload else {
+            panic!("unexpected payload: {:?}", any.signee.payload);
+        };
+        assert_eq!(unknown.typ, "from_future");
+        assert_eq!(unknown.rest["room"], Uuid::nil().to_string());
+        any.verify().unwrap();
+        assert_eq!(serde_json::to_string(&any).unwrap(), json);
+
+        let chat = ChatPayload {
+            room: Uuid::nil(),
+            sequence: None,
+            text: "hello".into(),
+        };
+        let item = WithSig::sign(&key, &mut rand::rngs::OsRng, chat).unwrap();
+        let json = serde_json::to_vec(&item).unwrap();
+        let any = WithSig::<AnyPayload>::ingest(&json, &ProtocolLimits::default()).unwrap();
+        assert!(matches!(any.signee.payload, AnyPayload::Chat(_)));
+    }
+
+    #[test]
+    fn chat_item_sql() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            r"
+            CREATE TABLE `chat` (
+                `room`      BLOB NOT NULL,
+                `user`      BLOB NOT NULL,
+                `timestamp` INTEGER NOT NULL,
+                `nonce`     INTEGER NOT NULL,
+                `version`   INTEGER NOT NULL,
+                `sig`       BLOB NOT NULL,
+                `sequence`  INTEGER,
+                `text`      TEXT NOT NULL
+            ) STRICT;
+            ",
+        )
+        .unwrap();
+
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let chat = ChatPayload {
+            room: Uuid::new_v4(),
+            sequence: Some(7),
+            text: "hello".into(),
+        };
+        let item = ChatItem::sign(&key, &mut rand::rngs::OsRng, chat).unwrap();
+        let cols = ChatItem::SQL_COLUMNS;
+        let names = item.to_sql_params().map(|(name, _)| name).join(", ");
+        let insert = format!("INSERT INTO `chat` ({cols}) VALUES ({names})");
+        conn.execute(&insert, &item.to_sql_params()[..]).unwrap();
+
+        let select = format!("SELECT {cols} FROM `chat`");
+        let read = |verify| conn.query_row(&select, [], |row| ChatItem::from_sql_row(row, verify));
+        let back = read(true).unwrap();
+        assert_eq!(back.sig, item.sig);
+        assert_eq!(
+            back.signee.canonical_bytes().unwrap(),
+            item.signee.canonical_bytes().unwrap(),
+        );
+
+        conn.execute("UPDATE `chat` SET `text` = 'forged'", [])
+            .unwrap();
+        assert_eq!(read(false).unwrap().signee.payload.text, "forged");
+        read(true).unwrap_err();
+    }
+
+    #[test]
+    fn stored_item_sql() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            r"
+            CREATE TABLE `chat` (
+                `room`          BLOB NOT NULL,
+                `user`          BLOB NOT NULL,
+                `timestamp`     INTEGER NOT NULL,
+                `nonce`         INTEGER NOT NULL,
+                `version`       INTEGER NOT NULL,
+                `sig`           BLOB NOT NULL,
+                `sequence`      INTEGER,
+                `text`          TEXT NOT NULL,
+                `received_at`   INTEGER NOT NULL
+            ) STRICT;
+            ",
+        )
+        .unwrap();
+
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let chat = ChatPayload {
+            room: Uuid::new_v4(),
+            sequence: None,
+            text: "hello".into(),
+        };
+        let item = ChatItem::sign(&key, &mut rand::rngs::OsRng, chat).unwrap();
+        let ts = item.signee.timestamp;
+        let stored = StoredItem::received_now(item);
+        assert!(stored.received_at >= ts);
+        // The receive time is independent of the signed one.
+        let stored = StoredItem {
+            received_at: 42,
+            ..stored
+        };
+
+        let json = serde_json::to_value(&stored).unwrap();
+        assert_eq!(json["received_at"], 42);
+        assert_eq!(json["item"]["signee"]["timestamp"], ts);
+
+        let cols = StoredItem::<ChatPayload>::SQL_COLUMNS;
+        let names = stored.to_sql_params().map(|(name, _)| name).join(", ");
+        let insert = format!("INSERT INTO `chat` ({cols}) VALUES ({names})");
+        conn.execute(&insert, &stored.to_sql_params()[..]).unwrap();
+        let back = conn
+            .query_row(&format!("SELECT {cols} FROM `chat`"), [], |row| {
+                StoredItem::<ChatPayload>::from_sql_row(row, true)
+            })
+            .unwrap();
+        assert_eq!(back.received_at, 42);
+        assert_eq!(back.item, stored.item);
+        assert_eq!(back.item.signee.timestamp, ts);
+    }
+
+    #[test]
+    fn admin_batch_limit() {
+        let room = Uuid::nil();
+        let batch = |n: usize| {
+            let op = serde_json::json!({ "typ": "set_title", "room": room, "title": "t" });
+            serde_json::json!({ "typ": "batch", "ops": vec![op; n], "room": room })
+        };
+        let parse = |v| serde_json::from_value::<RoomAdminPayload>(v);
+        let limits = ProtocolLimits::default();
+        let max = limits.max_batch_ops;
+        parse(batch(max)).unwrap().validate(&limits).unwrap();
+        parse(batch(max + 1))
+            .unwrap()
+            .validate(&limits)
+            .unwrap_err();
+        parse(batch(0)).unwrap().validate(&limits).unwrap_err();
+
+        // Limits above the default are not capped on parsing.
+        let larger = ProtocolLimits {
+            max_batch_ops: 100,
+            ..limits.clone()
+        };
+        parse(batch(100)).unwrap().validate(&larger).unwrap();
+        parse(batch(101)).unwrap().validate(&larger).unwrap_err();
+
+        let smaller = ProtocolLimits {
+            max_batch_ops: 2,
+            ..limits.clone()
+        };
+        parse(batch(2)).unwrap().validate(&smaller).unwrap();
+        parse(batch(3)).unwrap().validate(&smaller).unwrap_err();
+
+        let nested = serde_json::json!({ "typ": "batch", "ops": [batch(1)], "room": room });
+        parse(nested).unwrap_err();
+        let other_room = serde_json::json!({
+            "typ": "batch",
+            "ops": [{ "typ": "set_title", "room": Uuid::max(), "title": "t" }],
+            "room": room,
+        });
+        parse(other_room).unwrap().validate(&limits).unwrap_err();
+    }
+
+    #[test]
+    fn with_sig_identity() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let chat = |text: &str| ChatPayload {
+            room: Uuid::nil(),
+            sequence: None,
+            text: text.into(),
+        };
+        let a = ChatItem::sign(&key, &mut rand::rngs::OsRng, chat("a")).unwrap();
+        let b = ChatItem::sign(&key, &mut rand::rngs::OsRng, chat("b")).unwrap();
+        let a2 = serde_json::from_str::<ChatItem>(&serde_json::to_string(&a).unwrap()).unwrap();
+
+        let mut set = std::collections::HashSet::new();
+        assert!(set.insert(a));
+        assert!(set.insert(b));
+        assert!(!set.insert(a2));
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn chat_day_bucket() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let at = |timestamp| {
+            let signee = Signee {
+                nonce: 0,
+                payload: ChatPayload {
+                    room: Uuid::nil(),
+                    sequence: None,
+                    text: "hi".into(),
+                },
+                timestamp,
+                user: UserKey(key.verifying_key().to_bytes()),
+                version: PROTOCOL_VERSION,
+            };
+            ChatItem::sign_signee(&key, signee).unwrap().day_bucket()
+        };
+        assert_eq!(at(0), 0);
+        assert_eq!(at(86399), 0);
+        assert_eq!(at(86400), 1);
+        // 2024-01-01T00:00:00Z and one second before.
+        assert_eq!(at(1704067200), 19723);
+        assert_eq!(at(1704067199), 19722);
+        assert_eq!(at(u64::MAX), u32::MAX);
+    }
+
+    #[test]
+    fn verified_payload() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let chat = ChatPayload {
+            room: Uuid::nil(),
+            sequence: None,
+            text: "hello".into(),
+        };
+        let mut item = ChatItem::sign(&key, &mut rand::rngs::OsRng, chat).unwrap();
+        let opts = VerifyOptions::default();
+        assert_eq!(item.verified_payload(&opts).unwrap().text, "hello");
+
+        item.signee.payload.text = "tampered".into();
+        let err = item.verified_payload(&opts).unwrap_err();
+        assert!(matches!(err, VerifyError::InvalidSignature), "{err:?}");
+
+        item.signee.timestamp = 0;
+        let err = item.verified_payload(&opts).unwrap_err();
+        assert!(
+            matches!(err, VerifyError::ExpiredTimestamp { .. }),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn protocol_version() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let item = WithSig::sign(&key, &mut rand::rngs::OsRng, serde_json::Value::Null).unwrap();
+        assert_eq!(item.signee.version, PROTOCOL_VERSION);
+        item.verify().unwrap();
+
+        // Correctly signed, but in an unknown version.
+        let signee = Signee {
+            version: SORTED_VERSION + 1,
+            ..item.signee
+        };
+        let item = WithSig::sign_signee(&key, signee).unwrap();
+        let err = item
+            .verify_with_options(&VerifyOptions::default())
+            .unwrap_err();
+        assert!(
+            matches!(err, VerifyError::UnsupportedVersion(v) if v == SORTED_VERSION + 1),
+            "{err:?}",
+        );
+        item.verify_signature().unwrap_err();
+
+        // Items without a version are from before versioning.
+        let mut v = serde_json::to_value(&item).unwrap();
+        v["signee"].as_object_mut().unwrap().remove("version");
+        WithSig::<serde_json::Value>::try_from_value(v).unwrap_err();
+    }
+
+    #[test]
+    fn canonical_integer_boundaries() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let signee = Signee {
+            nonce: u32::MAX,
+            payload: (),
+            timestamp: u64::MAX,
+            user: UserKey::from_signing_key(&key),
+            version: PROTOCOL_VERSION,
+        };
+        let item = WithSig::sign_signee(&key, signee).unwrap();
+        item.verify_signature().unwrap();
+
+        let canonical = String::from_utf8(item.signee.canonical_bytes().unwrap()).unwrap();
+        assert!(
+            canonical.starts_with(
+                r#"{"nonce":4294967295,"payload":null,"timestamp":18446744073709551615,"#
+            ),
+            "{canonical}",
+        );
+
+        // Exact through a round-trip, so it still verifies.
+        let back =
+            serde_json::from_str::<WithSig<()>>(&serde_json::to_string(&item).unwrap()).unwrap();
+        assert_eq!(back.signee.nonce, u32::MAX);
+        assert_eq!(back.signee.timestamp, u64::MAX);
+        assert_eq!(back.signee.canonical_bytes().unwrap(), canonical.as_bytes());
+        back.verify_signature().unwrap();
+        let value = serde_json::to_value(&item).unwrap();
+        let back = WithSig::<serde_json::Value>::try_from_value(value).unwrap();
+        back.verify_signature().unwrap();
+    }
+
+    #[test]
+    fn canonical_envelope_bytes() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let item = WithSig::sign(&key, &mut rand::rngs::OsRng, ()).unwrap();
+        let sig_hex = hex::encode(item.sig);
+        let signee = String::from_utf8(item.signee.canonical_bytes().unwrap()).unwrap();
+        let envelope = String::from_utf8(item.canonical_envelope_bytes().unwrap()).unwrap();
+        assert_eq!(
+            envelope,
+            format!(r#"{{"sig":"{sig_hex}","signee":{signee}}}"#)
+        );
+        assert!(!signee.contains(&sig_hex));
+    }
+
+    #[test]
+    fn seconds_until_stale() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let now = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1000);
+        let item = WithSig::sign_at(&key, &mut rand::rngs::OsRng, (), now).unwrap();
+        assert_eq!(item.seconds_until_stale(1000, 90), 90);
+        assert_eq!(item.seconds_until_stale(1089, 90), 1);
+        assert_eq!(item.seconds_until_stale(1090, 90), 0);
+        assert_eq!(item.seconds_until_stale(1100, 90), -10);
+        assert_eq!(item.seconds_until_stale(0, u64::MAX), i64::MAX);
+    }
+
+    #[test]
+    fn auth_token_grace() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let at = |secs| SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        let token =
+            WithSig::sign_at(&key, &mut rand::rngs::OsRng, AuthPayload {}, at(1000)).unwrap();
+        let limits = ProtocolLimits {
+            timestamp_tolerance: 90,
+            ..ProtocolLimits::default()
+        };
+        let grace = std::time::Duration::from_secs(60);
+        let verify = |now| verify_auth_token_with_grace(&token, &limits, now, grace);
+
+        assert_eq!(verify(1000).unwrap(), AuthFreshness::Fresh);
+        assert_eq!(verify(1089).unwrap(), AuthFreshness::Fresh);
+        assert_eq!(verify(1090).unwrap(), AuthFreshness::NeedsRefresh);
+        assert_eq!(verify(1149).unwrap(), AuthFreshness::NeedsRefresh);
+        assert!(matches!(
+            verify(1150),
+            Err(VerifyError::ExpiredTimestamp { .. })
+        ));
+        // No grace for the future.
+        assert_eq!(verify(911).unwrap(), AuthFreshness::Fresh);
+        assert!(matches!(
+            verify(910),
+            Err(VerifyError::ExpiredTimestamp { .. })
+        ));
+
+        let mut forged = token;
+        forged.signee.user = UserKey([2; 32]);
+        verify_auth_token_with_grace(&forged, &limits, 1100, grace).unwrap_err();
+    }
+
+    #[test]
+    fn migrate_legacy_item() {
+        // Signed by a pre-versioning implementation.
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let signee = serde_json::json!({
+            "nonce": 42,
+            "payload": { "text": "legacy" },
+            "timestamp": 1_700_000_000,
+            "user": UserKey(key.verifying_key().to_bytes()),
+        });
+        let sig = key.sign(&serde_json::to_vec(&signee).unwrap()).to_bytes();
+        let legacy = serde_json::json!({ "sig": hex::encode(sig), "signee": signee });
+        WithSig::<serde_json::Value>::try_from_value(legacy.clone()).unwrap_err();
+
+        let item = migrate_legacy(legacy.clone()).unwrap();
+        assert_eq!(item.signee.version, LEGACY_VERSION);
+        assert_eq!(item.sig, sig);
+        // The migrated form round-trips and stays verifiable.
+        let stored = serde_json::to_value(&item).unwrap();
+        assert_eq!(stored["signee"]["version"], 0);
+        let back = WithSig::<serde_json::Value>::try_from_value(stored.clone()).unwrap();
+        back.verify_signature().unwrap();
+        assert_eq!(migrate_legacy(stored).unwrap(), item);
+        // Timestamps of legacy items are not special-cased.
+        back.verify().unwrap_err();
+
+        // Legacy items are only accepted for history, even if fresh.
+        let signee = serde_json::json!({
+            "nonce": 42,
+            "payload": { "text": "legacy" },
+            "timestamp": get_timestamp(),
+            "user": UserKey(key.verifying_key().to_bytes()),
+        });
+        let sig = key.sign(&serde_json::to_vec(&signee).unwrap()).to_bytes();
+        let fresh =
+            migrate_legacy(serde_json::json!({ "sig": hex::encode(sig), "signee": signee }))
+                .unwrap();
+        fresh.verify_signature().unwrap();
+        fresh.verify().unwrap_err();
+        assert!(matches!(
+            fresh.verify_with_options(&VerifyOptions::default()),
+            Err(VerifyError::UnsupportedVersion(LEGACY_VERSION)),
+        ));
+
+        let mut tampered = legacy;
+        tampered["signee"]["payload"]["text"] = "tampered".into();
+        migrate_legacy(tampered).unwrap_err();
+
+        // Current items are kept as-is.
+        let item = WithSig::sign(&key, &mut rand::rngs::OsRng, serde_json::Value::Null).unwrap();
+        let migrated = migrate_legacy(serde_json::to_value(&item).unwrap()).unwrap();
+        assert_eq!(migrated.signee.version, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn self_referential_target() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let chat = ChatPayload {
+            room: Uuid::nil(),
+            sequence: None,
+            text: "hello".into(),
+        };
+        let chat = ChatItem::sign(&key, &mut rand::rngs::OsRng, chat).unwrap();
+        let target = MessageId::of(&chat);
+
+        let delete = DeleteChatPayload {
+            room: Uuid::nil(),
+            target,
+        };
+        let delete = DeleteChatItem::sign(&key, &mut rand::rngs::OsRng, delete).unwrap();
+        let bytes = serde_json::to_vec(&delete).unwrap();
+        DeleteChatItem::ingest(&bytes, &ProtocolLimits::default()).unwrap();
+        // A self-reference cannot be signed in practice, since the id depends on the signature.
+        // Pretend the item were its own target.
+        delete.signee.payload.validate_with_id(&target).unwrap_err();
+
+        let edit = EditChatPayload {
+            room: Uuid::nil(),
+            target,
+            text: "edited".into(),
+        };
+        let edit = EditChatItem::sign(&key, &mut rand::rngs::OsRng, edit).unwrap();
+        let bytes = serde_json::to_vec(&edit).unwrap();
+        let any = WithSig::<AnyPayload>::ingest(&bytes, &ProtocolLimits::default()).unwrap();
+        assert!(matches!(any.signee.payload, AnyPayload::EditChat(_)));
+        let err = edit.signee.payload.validate_with_id(&target).unwrap_err();
+        assert_eq!(err.to_string(), "edit targets itself");
+        any.signee.payload.validate_with_id(&target).unwrap_err();
+    }
+
+    #[test]
+    fn report_payload() {
+        let (room, target) = (Uuid::nil(), MessageId([1; 32]));
+        let report = |reason: String| ReportPayload {
+            reason,
+            room,
+            target,
+        };
+        let json = serde_json::to_string(&report("spam".into())).unwrap();
+        assert_eq!(
+            json,
+            format!(r#"{{"typ":"report","reason":"spam","room":"{room}","target":"{target}"}}"#),
+        );
+        let back = serde_json::from_str::<ReportPayload>(&json).unwrap();
+        assert_eq!((back.reason.as_str(), back.target), ("spam", target));
+
+        let limits = ProtocolLimits::default();
+        let max = ReportPayload::MAX_REASON_LEN;
+        report("x".repeat(max)).validate(&limits).unwrap();
+        report("x".repeat(max + 1)).validate(&limits).unwrap_err();
+        report(String::new()).validate(&limits).unwrap_err();
+    }
+
+    #[test]
+    fn ingest_many_partial() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let sign = |text: &str| {
+            let chat = ChatPayload {
+                room: Uuid::nil(),
+                sequence: None,
+                text: text.into(),
+            };
+            ChatItem::sign(&key, &mut rand::rngs::OsRng, chat).unwrap()
+        };
+        let mut tampered = sign("b");
+        tampered.signee.payload.text = "c".into();
+        let items = vec![sign("a"), tampered, sign(""), sign("d")];
+
+        let ret = ingest_many(items, &VerifyOptions::default());
+        assert_eq!(ret.len(), 4);
+        assert_eq!(ret[0].as_ref().unwrap().signee.payload.text, "a");
+        assert!(matches!(ret[1], Err(VerifyError::InvalidSignature)));
+        assert!(matches!(ret[2], Err(VerifyError::InvalidPayload(_))));
+        assert_eq!(ret[3].as_ref().unwrap().signee.payload.text, "d");
+    }
+
+    #[test]
+    fn permission_diff() {
+        let old = MemberPermission::POST_CHAT | MemberPermission::ADD_MEMBER;
+        let new = MemberPermission::POST_CHAT | MemberPermission::REMOVE_MEMBER;
+        assert_eq!(
+            old.diff(new),
+            (
+                MemberPermission::REMOVE_MEMBER,
+                MemberPermission::ADD_MEMBER
+            )
+        );
+        assert_eq!(
+            new.diff(old),
+            (
+                MemberPermission::ADD_MEMBER,
+                MemberPermission::REMOVE_MEMBER
+            )
+        );
+        assert_eq!(
+            old.diff(old),
+            (MemberPermission::empty(), MemberPermission::empty())
+        );
+    }
+
+    #[test]
+    fn owner_permission() {
+        assert_eq!(MemberPermission::OWNER, MemberPermission::ALL);
+        assert!(MemberPermission::OWNER.is_owner());
+        assert!(!(MemberPermission::ALL - MemberPermission::POST_CHAT).is_owner());
+        assert_eq!(MemberPermission::OWNER.bits(), u64::MAX);
+
+        let payload = |permission| CreateRoomPayload {
+            attrs: RoomAttrs::empty(),
+            members: RoomMemberList(vec![RoomMember {
+                permission,
+                user: UserKey([1; 32]),
+            }]),
+            title: "test".into(),
+        };
+        let owner = UserKey([1; 32]);
+        payload(MemberPermission::OWNER)
+            .check_creator(&owner)
+            .unwrap();
+        payload(MemberPermission::POST_CHAT | MemberPermission::ADD_MEMBER)
+            .check_creator(&owner)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn sign_before_epoch() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let before_epoch = SystemTime::UNIX_EPOCH - std::time::Duration::from_secs(1);
+        let item = WithSig::sign_at(
+            &key,
+            &mut rand::rngs::OsRng,
+            serde_json::Value::Null,
+            before_epoch,
+        )
+        .unwrap();
+        assert_eq!(item.signee.timestamp, 0);
+        item.verify_signature().unwrap();
+        item.verify().unwrap_err();
+
+        let at = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1724966284);
+        let item =
+            WithSig::sign_at(&key, &mut rand::rngs::OsRng, serde_json::Value::Null, at).unwrap();
+        assert_eq!(item.signee.timestamp, 1724966284);
+    }
+
+    #[test]
+    fn block_payload() {
+        let target = UserKey([1; 32]);
+        let payload = BlockPayload {
+            block: true,
+            target: target.clone(),
+        };
+        let json = serde_json::to_string(&payload).unwrap();
+        assert_eq!(
+            json,
+            format!(r#"{{"typ":"block","block":true,"target":"{target}"}}"#),
+        );
+        let any = serde_json::from_str::<AnyPayload>(&json).unwrap();
+        assert!(matches!(any, AnyPayload::Block(_)));
+
+        let mut blocked = HashSet::new();
+        assert!(payload.apply(&mut blocked));
+        assert!(!payload.apply(&mut blocked));
+        assert!(blocked.contains(&target));
+        let unblock = serde_json::from_str::<BlockPayload>(
+            &json.replace(r#""block":true"#, r#""block":false"#),
+        )
+        .unwrap();
+        assert!(unblock.apply(&mut blocked));
+        assert!(!unblock.apply(&mut blocked));
+        assert!(blocked.is_empty());
+    }
+
+    #[test]
+    fn referenced_keys() {
+        let key = |b: u8| UserKey([b; 32]);
+        let room = Uuid::nil();
+        let mention = |b: u8| format!("@{}", key(b));
+
+        let chat = ChatPayload {
+            room,
+            sequence: None,
+            text: format!("{} and {}, again {}", mention(1), mention(2), mention(1)),
+        };
+        assert_eq!(chat.referenced_keys(), [key(1), key(2)]);
+        let edit = EditChatPayload {
+            room,
+            target: MessageId([0; 32]),
+            text: format!("{}x not a mention", mention(3)),
+        };
+        assert!(edit.referenced_keys().is_empty());
+
+        let add = |b| RoomAdminPayload::AddMember {
+            permission: MemberPermission::POST_CHAT,
+            room,
+            user: key(b),
+        };
+        assert_eq!(add(1).referenced_keys(), [key(1)]);
+        let remove = RoomAdminPayload::RemoveMember { room, user: key(2) };
+        assert_eq!(remove.referenced_keys(), [key(2)]);
+        let set_title = RoomAdminPayload::SetTitle {
+            room,
+            title: "t".into(),
+        };
+        assert!(set_title.referenced_keys().is_empty());
+        let batch = RoomAdminPayload::Batch {
+            ops: AdminBatch(vec![add(1), remove, add(1)]),
+            room,
+        };
+        assert_eq!(batch.referenced_keys(), [key(1), key(2)]);
+
+        let block = BlockPayload {
+            block: true,
+            target: key(4),
+        };
+        assert_eq!(block.referenced_keys(), [key(4)]);
+        let create = CreateRoomPayload {
+            attrs: RoomAttrs::empty(),
+            members: RoomMemberList(vec![
+                RoomMember {
+                    permission: MemberPermission::OWNER,
+                    user: key(1),
+                },
+                RoomMember {
+                    permission: MemberPermission::POST_CHAT,
+                    user: key(5),
+                },
+            ]),
+            title: "t".into(),
+        };
+        assert_eq!(create.referenced_keys(), [key(1), key(5)]);
+        let room_key = RoomKeyPayload {
+            recipients: vec![RoomKeyRecipient {
+                sealed_key: vec![0; 4],
+                user: key(6),
+            }],
+            room,
+        };
+        assert_eq!(room_key.referenced_keys(), [key(6)]);
+        assert!(LeavePayload { room }.referenced_keys().is_empty());
+
+        let any =
+            serde_json::from_value::<AnyPayload>(serde_json::to_value(&chat).unwrap()).unwrap();
+        assert_eq!(any.referenced_keys(), [key(1), key(2)]);
+        let any = serde_json::from_str::<AnyPayload>(r#"{"typ":"unknown"}"#).unwrap();
+        assert!(any.referenced_keys().is_empty());
+
+        // The author goes first, and is not repeated if also mentioned.
+        let signing = SigningKey::from_bytes(&[1; 32]);
+        let author = UserKey(signing.verifying_key().to_bytes());
+        let chat = ChatPayload {
+            room,
+            sequence: None,
+            text: format!("@{author} {}", mention(2)),
+        };
+        let item = ChatItem::sign(&signing, &mut rand::rngs::OsRng, chat).unwrap();
+        assert_eq!(item.referenced_keys(), [author, key(2)]);
+    }
+
+    #[test]
+    fn device_registration() {
+        let primary = SigningKey::from_bytes(&[1; 32]);
+        let device = SigningKey::from_bytes(&[2; 32]);
+        let register = |expires_at| {
+            let payload = DeviceRegistrationPayload {
+                device_key: UserKey::from_signing_key(&device),
+                expires_at,
+                label: "phone".into(),
+            };
+            payload.validate(&ProtocolLimits::default()).unwrap();
+            DeviceRegistrationItem::sign(&primary, &mut rand::rngs::OsRng, payload).unwrap()
+        };
+        let item = WithSig::sign(&device, &mut rand::rngs::OsRng, ()).unwrap();
+
+        let reg = register(Some(1000));
+        let json = serde_json::to_value(&reg).unwrap();
+        assert_eq!(json["signee"]["payload"]["typ"], "device_reg");
+        let any = serde_json::from_value::<AnyPayload>(json["signee"]["payload"].clone()).unwrap();
+        assert!(matches!(any, AnyPayload::DeviceRegistration(_)));
+
+        let identity = reg.identity_of(&item, 999).unwrap();
+        assert_eq!(*identity, UserKey::from_signing_key(&primary));
+        let err = reg.identity_of(&item, 1000).unwrap_err();
+        assert_eq!(err.to_string(), "device registration expired");
+        register(None).identity_of(&item, u64::MAX).unwrap();
+
+        // Items from other keys, including the primary key itself, are not mapped.
+        let other = WithSig::sign(&primary, &mut rand::rngs::OsRng, ()).unwrap();
+        reg.identity_of(&other, 999).unwrap_err();
+
+        // A registration not signed by the primary key.
+        let mut forged = register(None);
+        forged.signee.user = UserKey([3; 32]);
+        forged.identity_of(&item, 999).unwrap_err();
+    }
+
+    #[test]
+    fn receipt_serde() {
+        let (room, up_to) = (Uuid::nil(), MessageId([1; 32]));
+        let json = serde_json::to_string(&DeliveryReceiptPayload { room, up_to }).unwrap();
+        assert_eq!(
+            json,
+            format!(r#"{{"typ":"delivered","room":"{room}","up_to":"{up_to}"}}"#),
+        );
+        let back = serde_json::from_str::<DeliveryReceiptPayload>(&json).unwrap();
+        assert_eq!(back.up_to, up_to);
+        let any = serde_json::from_str::<AnyPayload>(&json).unwrap();
+        assert!(matches!(any, AnyPayload::DeliveryReceipt(_)));
+
+        let json = serde_json::to_string(&ReadReceiptPayload { room, up_to }).unwrap();
+        assert_eq!(
+            json,
+            format!(r#"{{"typ":"read_receipt","room":"{room}","up_to":"{up_to}"}}"#),
+        );
+        let any = serde_json::from_str::<AnyPayload>(&json).unwrap();
+        assert!(matches!(any, AnyPayload::ReadReceipt(_)));
+    }
+
+    #[test]
+    fn forward() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let original = ChatItem::sign(
+            &key,
+            &mut rand::rngs::OsRng,
+            ChatPayload {
+                room: Uuid::new_v4(),
+                sequence: None,
+                text: "hello".into(),
+            },
+        )
+        .unwrap();
+        let forward = ForwardPayload {
+            original: Box::new(original),
+            room: Uuid::nil(),
+        };
+        let limits = ProtocolLimits::default();
+        forward.validate(&limits).unwrap();
+        assert_eq!(forward.referenced_keys(), [UserKey::from_signing_key(&key)]);
+
+        let json = serde_json::to_string(&forward).unwrap();
+        assert!(
+            json.starts_with(r#"{"typ":"forward","original":{"sig":"#),
+            "{json}"
+        );
+        let any = serde_json::from_str::<AnyPayload>(&json).unwrap();
+        assert!(matches!(any, AnyPayload::Forward(_)));
+        any.validate(&limits).unwrap();
+
+        let mut tampered = forward;
+        tampered.original.signee.payload.text = "tampered".into();
+        let err = tampered.validate(&limits).unwrap_err();
+        assert_eq!(err.to_string(), "invalid original message");
+    }
+
+    #[test]
+    fn pin_serde() {
+        let (room, target) = (Uuid::nil(), MessageId([1; 32]));
+        let json = serde_json::to_string(&PinPayload {
+            pin: true,
+            room,
+            target,
+        })
+        .unwrap();
+        assert_eq!(
+            json,
+            format!(r#"{{"typ":"pin","pin":true,"room":"{room}","target":"{target}"}}"#),
+        );
+        let back = serde_json::from_str::<PinPayload>(&json).unwrap();
+        assert!(back.pin);
+        assert_eq!(back.target, target);
+        let any = serde_json::from_str::<AnyPayload>(&json).unwrap();
+        assert!(matches!(any, AnyPayload::Pin(_)));
+    }
+}